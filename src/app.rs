@@ -1,24 +1,142 @@
-use crate::config::Config;
+use crate::config::{Config, Credentials};
+use crate::metrics::Metrics;
+use crate::plan::Conflict;
 use crate::technitium;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use serde::Serialize;
 use serde_json::json;
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock, watch};
+use tracing::{info, warn};
+
+/// The step of the startup the webhook is in.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupState {
+    /// Logging in to Technitium DNS.
+    #[default]
+    Connecting,
+    /// Checking that the managed zones exist, and creating the missing ones.
+    Preparing,
+    Ready,
+    /// Gave up after the startup deadline.
+    Failed,
+}
+
+/// The startup progress, as reported by the health check.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct StartupStatus {
+    pub state: StartupState,
+    pub attempt: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
 
 pub struct AppState {
     pub config: Config,
-    pub is_ready: RwLock<bool>,
-    pub client: RwLock<technitium::TechnitiumClient>,
+    pub startup: watch::Sender<StartupStatus>,
+    pub client: technitium::TechnitiumClient,
+    /// The current credentials, which change when the credential files are rotated.
+    pub credentials: RwLock<Credentials>,
+    pub credentials_changed: Notify,
+    pub metrics: Metrics,
 }
 
 impl AppState {
+    pub fn new(
+        config: Config,
+        credentials: Credentials,
+    ) -> Result<Self, technitium::TechnitiumError> {
+        if config.client_options.accept_invalid_certs {
+            warn!("TLS certificate verification of the Technitium DNS server is disabled.");
+        }
+        let client = technitium::TechnitiumClient::new(
+            config.technitium_url.clone(),
+            Default::default(), // no token initially
+            &config.client_options,
+        )?
+        .with_retry_policy(config.retry_policy)
+        .with_circuit_breaker(config.circuit_breaker);
+        Ok(Self {
+            credentials: RwLock::new(credentials),
+            config,
+            startup: watch::Sender::new(StartupStatus::default()),
+            client,
+            credentials_changed: Notify::new(),
+            metrics: Default::default(),
+        })
+    }
+
     pub async fn ensure_ready(&self) -> Result<(), AppError> {
-        if !*self.is_ready.read().await {
+        if self.startup.borrow().state != StartupState::Ready {
             return Err(AppError::NotReady);
         }
         Ok(())
     }
+
+    /// Authenticates with the current credentials, and switches the client to the new
+    /// session token, or the API token, without interrupting requests in flight.
+    ///
+    /// An API token is checked before switching to it, as it's never renewed, so that a bad
+    /// token is reported right away and a rotated one doesn't replace a working one.
+    pub async fn authenticate(&self) -> Result<(), technitium::TechnitiumError> {
+        let token = match self.credentials.read().await.clone() {
+            Credentials::Password { username, password } => {
+                let login_payload = technitium::LoginPayload { username, password };
+                let token = self.client.login(login_payload).await?.token;
+                info!("Successfully logged into Technitium DNS server with received token.");
+                token
+            }
+            Credentials::Token(token) => {
+                let session = self
+                    .client
+                    .with_token(token.clone())
+                    .get_session(technitium::GetSessionPayload::default())
+                    .await?;
+                info!(
+                    "Using API token of user {} for Technitium DNS server.",
+                    session.username
+                );
+                token
+            }
+        };
+
+        self.client.set_token(token);
+        Ok(())
+    }
+
+    /// Sends a request with the client.
+    ///
+    /// When Technitium rejects the session token, for example after a restart, logs in
+    /// again and retries the request once.
+    pub async fn with_client<T, F, Fut>(&self, request: F) -> Result<T, technitium::TechnitiumError>
+    where
+        F: Fn(technitium::TechnitiumClient) -> Fut,
+        Fut: Future<Output = Result<T, technitium::TechnitiumError>>,
+    {
+        let res = request(self.client.clone()).await;
+        let is_password = matches!(*self.credentials.read().await, Credentials::Password { .. });
+        match res {
+            // API tokens can't be renewed, so there's no point in retrying with them
+            Err(technitium::TechnitiumError::InvalidToken) if is_password => {
+                warn!("Technitium DNS server rejected the session token, logging in again.");
+                self.authenticate().await?;
+                request(self.client.clone()).await
+            }
+            res => res,
+        }
+    }
+}
+
+/// An endpoint in a change request that is outside of the scope managed by the webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopeViolation {
+    #[serde(rename = "dnsName")]
+    pub dns_name: String,
+    #[serde(rename = "recordType")]
+    pub record_type: String,
+    pub reason: String,
 }
 
 #[derive(Debug, Error)]
@@ -27,28 +145,254 @@ pub enum AppError {
     NotReady,
     #[error("Failed to serialize JSON: {0}")]
     JsonSerializeError(#[from] serde_json::Error),
+    #[error("Technitium server is unavailable. Try again later.")]
+    TechnitiumUnavailable,
     #[error("Failed to communicate with Technitium server: {0}")]
-    TechnitiumError(#[from] technitium::TechnitiumError),
+    TechnitiumError(technitium::TechnitiumError),
+    #[error(
+        "Refusing to delete or update {changes} records, which exceeds the limit of {limit} records. Set ALLOW_MASS_CHANGES=true to apply intentional mass changes."
+    )]
+    ChangeLimitExceeded { changes: usize, limit: usize },
+    #[error("Refusing to apply changes to {} records outside of the managed scope", .0.len())]
+    OutOfScope(Vec<ScopeViolation>),
+    #[error("Conflicting records: {}", .0.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; "))]
+    RecordConflict(Vec<Conflict>),
+}
+
+impl From<technitium::TechnitiumError> for AppError {
+    fn from(err: technitium::TechnitiumError) -> Self {
+        match err {
+            technitium::TechnitiumError::CircuitOpen => AppError::TechnitiumUnavailable,
+            err => AppError::TechnitiumError(err),
+        }
+    }
 }
 
 // Implement IntoResponse for our custom error to control the HTTP response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        let violations = match &self {
+            AppError::OutOfScope(violations) => Some(violations.clone()),
+            _ => None,
+        };
         let (status, error_message) = match self {
             AppError::NotReady => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
+            AppError::TechnitiumUnavailable => (StatusCode::SERVICE_UNAVAILABLE, self.to_string()),
             AppError::JsonSerializeError(_) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
             }
             AppError::TechnitiumError(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+            AppError::ChangeLimitExceeded { .. } => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string())
+            }
+            AppError::OutOfScope(_) => (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()),
+            AppError::RecordConflict(_) => (StatusCode::CONFLICT, self.to_string()),
         };
 
-        (
-            status,
-            json!({
+        let body = match violations {
+            Some(violations) => json!({
+                "error": error_message,
+                "violations": violations,
+            }),
+            None => json!({
                 "error": error_message,
+            }),
+        };
+
+        (status, body.to_string()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_with_client_logs_in_again_on_invalid_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let expired = server
+            .mock("POST", "/api/zones/records/get")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "expired".into(),
+            ))
+            .with_body(json!({"status": "invalid-token"}).to_string())
+            .expect(1)
+            .create();
+        let login = server
+            .mock("POST", "/api/user/login")
+            .with_body(
+                json!({
+                    "status": "ok",
+                    "displayName": "Administrator",
+                    "username": "admin",
+                    "token": "renewed",
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        let renewed = server
+            .mock("POST", "/api/zones/records/get")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "renewed".into(),
+            ))
+            .with_body(
+                json!({
+                    "status": "ok",
+                    "response": {
+                        "zone": {
+                            "name": "example.com",
+                            "type": "Primary",
+                            "disabled": false,
+                        },
+                        "records": [],
+                    },
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let app_state = AppState {
+            config: Config {
+                technitium_url: server.url(),
+                ..Default::default()
+            },
+            startup: watch::Sender::new(StartupStatus {
+                state: StartupState::Ready,
+                ..Default::default()
+            }),
+            client: technitium::TechnitiumClient::new(
+                server.url(),
+                "expired".to_string(),
+                &technitium::ClientOptions::default(),
+            )
+            .unwrap(),
+            credentials: RwLock::new(Credentials::Password {
+                username: "admin".to_string(),
+                password: "admin".to_string(),
+            }),
+            credentials_changed: Notify::new(),
+            metrics: Default::default(),
+        };
+
+        let res = app_state
+            .with_client(|client| async move {
+                client
+                    .get_records(technitium::GetRecordsPayload {
+                        domain: "example.com".to_string(),
+                        ..Default::default()
+                    })
+                    .await
             })
-            .to_string(),
+            .await
+            .unwrap();
+
+        expired.assert();
+        login.assert();
+        renewed.assert();
+        assert!(res.records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_with_client_does_not_retry_with_api_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mock = server
+            .mock("POST", "/api/zones/records/get")
+            .with_body(json!({"status": "invalid-token"}).to_string())
+            .expect(1)
+            .create();
+
+        let app_state = AppState {
+            config: Config {
+                technitium_url: server.url(),
+                ..Default::default()
+            },
+            startup: watch::Sender::new(StartupStatus {
+                state: StartupState::Ready,
+                ..Default::default()
+            }),
+            client: technitium::TechnitiumClient::new(
+                server.url(),
+                "revoked".to_string(),
+                &technitium::ClientOptions::default(),
+            )
+            .unwrap(),
+            credentials: RwLock::new(Credentials::Token("revoked".to_string())),
+            credentials_changed: Notify::new(),
+            metrics: Default::default(),
+        };
+
+        let res = app_state
+            .with_client(|client| async move {
+                client
+                    .get_records(technitium::GetRecordsPayload::default())
+                    .await
+            })
+            .await;
+
+        mock.assert();
+        assert!(matches!(
+            res,
+            Err(technitium::TechnitiumError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_keeps_working_token_when_rotated_one_is_rejected() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mut session = |token: &str, body: serde_json::Value| {
+            server
+                .mock("POST", "/api/user/session/get")
+                .match_body(mockito::Matcher::UrlEncoded("token".into(), token.into()))
+                .with_body(body.to_string())
+                .expect(1)
+                .create()
+        };
+        let rotated = session("rotated", json!({"status": "invalid-token"}));
+        let working = session(
+            "working",
+            json!({"status": "ok", "displayName": "External DNS", "username": "external-dns"}),
+        );
+
+        let app_state = AppState::new(
+            Config {
+                technitium_url: server.url(),
+                ..Default::default()
+            },
+            Credentials::Token("rotated".to_string()),
         )
-            .into_response()
+        .unwrap();
+        app_state.client.set_token("working".to_string());
+
+        let res = app_state.authenticate().await;
+        assert!(matches!(
+            res,
+            Err(technitium::TechnitiumError::InvalidToken)
+        ));
+        app_state
+            .client
+            .get_session(technitium::GetSessionPayload::default())
+            .await
+            .unwrap();
+
+        rotated.assert();
+        working.assert();
+    }
+
+    #[test]
+    fn test_circuit_open_is_service_unavailable() {
+        let err = AppError::from(technitium::TechnitiumError::CircuitOpen);
+        assert!(matches!(err, AppError::TechnitiumUnavailable));
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
     }
 }
@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+/// Exponential backoff, doubling the delay after every attempt up to a maximum.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    max: Duration,
+    next: Duration,
+    jitter: bool,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            max,
+            next: initial,
+            jitter: false,
+        }
+    }
+
+    /// Randomises every delay to somewhere between half and all of it, so that clients
+    /// failing at the same time don't all retry at the same time.
+    pub fn with_jitter(mut self) -> Self {
+        self.jitter = true;
+        self
+    }
+
+    /// Returns the delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        if self.jitter {
+            delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays = (0..5).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(5)).with_jitter();
+        for expected in [1, 2, 4, 5, 5].map(Duration::from_secs) {
+            let delay = backoff.next_delay();
+            assert!(delay >= expected / 2 && delay <= expected, "{delay:?}");
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::app::AppState;
+use crate::handlers;
+use crate::models::Changes;
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tracing::error;
+
+/// Exit code when the configuration is invalid.
+pub const EXIT_INVALID_CONFIG: u8 = 2;
+/// Exit code when the Technitium DNS server can't be reached or logged in to.
+pub const EXIT_CONNECTION_FAILED: u8 = 3;
+/// Exit code when a managed zone doesn't exist in the Technitium DNS server.
+pub const EXIT_MISSING_ZONE: u8 = 4;
+
+/// ExternalDNS webhook provider for Technitium DNS.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Path to a TOML configuration file.
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Run the webhook server (the default).
+    Serve,
+    /// Validate the configuration and the connection to Technitium DNS, then exit.
+    Check,
+    /// Print the endpoints the webhook reports to external-dns.
+    List,
+    /// Apply the changes from an external-dns changes JSON file.
+    Apply {
+        /// Path to the changes JSON file.
+        file: PathBuf,
+    },
+}
+
+async fn connect(app_state: &AppState) -> Result<(), ExitCode> {
+    app_state.authenticate().await.map_err(|e| {
+        error!("Failed to log in to Technitium DNS server: {}", e);
+        ExitCode::from(EXIT_CONNECTION_FAILED)
+    })
+}
+
+/// Checks that the webhook can log in, and that all managed zones exist.
+pub async fn check(app_state: &AppState) -> ExitCode {
+    if let Err(code) = connect(app_state).await {
+        return code;
+    }
+
+    let mut missing = Vec::new();
+    for zone in &app_state.config.zones {
+        match crate::check_zone_existence(app_state, &zone.name).await {
+            Ok(true) => {}
+            Ok(false) => missing.push(zone.name.as_str()),
+            Err(e) => {
+                error!("Failed to list zones: {}", e);
+                return ExitCode::from(EXIT_CONNECTION_FAILED);
+            }
+        }
+    }
+    if !missing.is_empty() {
+        error!(
+            "Zones {} don't exist in Technitium DNS server, they're created when serving.",
+            missing.join(", ")
+        );
+        return ExitCode::from(EXIT_MISSING_ZONE);
+    }
+
+    println!(
+        "Configuration is valid, and all {} zones exist in Technitium DNS server.",
+        app_state.config.zones.len()
+    );
+    ExitCode::SUCCESS
+}
+
+/// Prints the endpoints returned from `GET /records` as JSON.
+pub async fn list(app_state: &AppState) -> ExitCode {
+    if let Err(code) = connect(app_state).await {
+        return code;
+    }
+
+    let endpoints = match handlers::fetch_endpoints(app_state).await {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            error!("Failed to fetch records: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match serde_json::to_string_pretty(&endpoints) {
+        Ok(json) => {
+            println!("{json}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to serialize JSON: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Applies the changes from the file like `POST /records`, including in dry run mode.
+pub async fn apply(app_state: &AppState, file: &Path) -> ExitCode {
+    let changes = match read_changes(file) {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(code) = connect(app_state).await {
+        return code;
+    }
+
+    match handlers::apply_changes(app_state, changes).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Failed to apply changes: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read_changes(file: &Path) -> Result<Changes, String> {
+    let contents =
+        fs::read_to_string(file).map_err(|e| format!("Failed to read {}: {e}", file.display()))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid changes in {}: {e}", file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::try_parse_from(["webhook"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from(["webhook", "check", "--config", "webhook.toml"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Check)));
+        assert_eq!(cli.config, Some(PathBuf::from("webhook.toml")));
+
+        let cli = Cli::try_parse_from(["webhook", "apply", "changes.json"]).unwrap();
+        assert!(
+            matches!(cli.command, Some(Command::Apply { file }) if file == Path::new("changes.json"))
+        );
+
+        assert!(Cli::try_parse_from(["webhook", "apply"]).is_err());
+        assert!(Cli::try_parse_from(["webhook", "unknown"]).is_err());
+    }
+}
@@ -1,14 +1,206 @@
+use crate::config_file::{FileConfig, TechnitiumFileConfig, ZoneFileConfig};
+use crate::filter::{self, DomainFilter};
+use crate::normalize::to_ascii;
+use crate::pattern::RecordSelector;
+use crate::technitium::{CircuitBreakerPolicy, ClientOptions, RetryPolicy};
+use crate::ttl::{TtlLimits, TtlPolicy, TtlRule};
+use reqwest::{Certificate, Identity, Proxy};
 use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How records that are disabled in Technitium are reported to external-dns.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DisabledRecordsPolicy {
+    /// Disabled records are treated as absent.
+    Hide,
+    /// Disabled records are treated as present, like any other record.
+    #[default]
+    Show,
+    /// Disabled records are treated as absent, and are re-enabled when
+    /// external-dns asks for a record with the same data to be created.
+    Enable,
+}
+
+impl FromStr for DisabledRecordsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hide" => Ok(Self::Hide),
+            "show" => Ok(Self::Show),
+            "enable" => Ok(Self::Enable),
+            _ => Err(format!("unknown disabled records policy: {s}")),
+        }
+    }
+}
+
+/// Which kinds of changes the webhook applies.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Policy {
+    /// Creates, updates and deletes are applied.
+    #[default]
+    Sync,
+    /// Creates and updates are applied, deletes are dropped.
+    UpsertOnly,
+    /// Creates are applied, updates and deletes are dropped.
+    CreateOnly,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sync" => Ok(Self::Sync),
+            "upsert-only" => Ok(Self::UpsertOnly),
+            "create-only" => Ok(Self::CreateOnly),
+            _ => Err(format!("unknown policy: {s}")),
+        }
+    }
+}
+
+/// Upper bound on the number of records a single batch may delete or update.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeLimit {
+    /// An absolute number of records.
+    Count(usize),
+    /// A percentage of the records currently in the zone.
+    Percent(f64),
+}
+
+impl ChangeLimit {
+    /// Returns the maximum number of changes, given the current number of records.
+    pub fn limit(&self, current: usize) -> usize {
+        match *self {
+            Self::Count(count) => count,
+            Self::Percent(percent) => (current as f64 * percent / 100.0).floor() as usize,
+        }
+    }
+}
+
+impl FromStr for ChangeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(Self::Percent(percent)),
+                _ => Err(format!("invalid percentage: {s}")),
+            },
+            None => s
+                .parse()
+                .map(Self::Count)
+                .map_err(|_| format!("invalid change count: {s}")),
+        }
+    }
+}
+
+/// What happens to change requests for records outside of the managed scope.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ScopeViolations {
+    /// The whole batch is refused with an error listing the violations.
+    #[default]
+    Reject,
+    /// Out of scope records are skipped, and the rest of the batch is applied.
+    Skip,
+}
+
+impl FromStr for ScopeViolations {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!("unknown scope violations handling: {s}")),
+        }
+    }
+}
+
+/// How the webhook authenticates with the Technitium DNS server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    /// Logs in with a username and password, and periodically renews the session token.
+    Password { username: String, password: String },
+    /// Uses a pre-issued API token, which doesn't need renewing.
+    Token(String),
+}
+
+impl Credentials {
+    /// Reads the credentials from the environment, the files named by the `*_FILE` variables
+    /// and the configuration file, which are all read again whenever this is called.
+    pub fn reload(config_file: Option<&Path>) -> Result<Self, String> {
+        let file = match config_file {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        Self::load(&|name| env::var(name).ok(), &file.technitium)
+    }
+
+    fn load(env: &Env, file: &TechnitiumFileConfig) -> Result<Self, String> {
+        let token = env_or_file(env, "TECHNITIUM_TOKEN")?.or_else(|| file.token.clone());
+        let username = env_or_file(env, "TECHNITIUM_USERNAME")?.or_else(|| file.username.clone());
+        let password = env_or_file(env, "TECHNITIUM_PASSWORD")?.or_else(|| file.password.clone());
+        match (token, username, password) {
+            (Some(token), None, None) => Ok(Self::Token(token)),
+            (Some(_), _, _) => Err(
+                "TECHNITIUM_TOKEN can't be used together with TECHNITIUM_USERNAME and TECHNITIUM_PASSWORD"
+                    .to_string(),
+            ),
+            (None, Some(username), Some(password)) => Ok(Self::Password { username, password }),
+            (None, None, _) => Err("Missing TECHNITIUM_USERNAME or TECHNITIUM_TOKEN".to_string()),
+            (None, _, None) => Err("Missing TECHNITIUM_PASSWORD".to_string()),
+        }
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::Password {
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+/// A DNS zone managed by the webhook.
+#[derive(Debug, Clone, Default)]
+pub struct Zone {
+    pub name: String,
+    /// The TTL policy of the zone's records, including the global limits and rules.
+    pub ttl_policy: TtlPolicy,
+    /// Records of the zone that are never changed, in addition to the global ones.
+    pub protected_records: Vec<RecordSelector>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub listen_address: String,
     pub listen_port: String,
     pub technitium_url: String,
-    pub technitium_username: String,
-    pub technitium_password: String,
-    pub zone: String,
-    pub domain_filters: Option<Vec<String>>,
+    /// Whether any of the credentials are read from files, which are watched for changes.
+    pub watch_credentials: bool,
+    pub config_file: Option<PathBuf>,
+    pub zones: Vec<Zone>,
+    pub domain_filter: DomainFilter,
+    pub scope_violations: ScopeViolations,
+    pub disabled_records: DisabledRecordsPolicy,
+    pub protected_records: Vec<RecordSelector>,
+    pub policy: Policy,
+    pub max_changes: Option<ChangeLimit>,
+    pub allow_mass_changes: bool,
+    pub dry_run: bool,
+    pub ttl_policy: TtlPolicy,
+    /// How long to keep retrying to connect to Technitium at startup, or forever if unset.
+    pub startup_timeout: Option<Duration>,
+    pub retry_policy: RetryPolicy,
+    pub circuit_breaker: CircuitBreakerPolicy,
+    pub client_options: ClientOptions,
 }
 
 impl Default for Config {
@@ -17,32 +209,796 @@ impl Default for Config {
             listen_address: "0.0.0.0".to_string(),
             listen_port: "3000".to_string(),
             technitium_url: String::new(),
-            technitium_username: String::new(),
-            technitium_password: String::new(),
-            zone: String::new(),
-            domain_filters: None,
+            watch_credentials: false,
+            config_file: None,
+            zones: Vec::new(),
+            domain_filter: DomainFilter::default(),
+            scope_violations: ScopeViolations::default(),
+            disabled_records: DisabledRecordsPolicy::default(),
+            protected_records: Vec::new(),
+            policy: Policy::default(),
+            max_changes: None,
+            allow_mass_changes: false,
+            dry_run: false,
+            ttl_policy: TtlPolicy::default(),
+            startup_timeout: None,
+            retry_policy: RetryPolicy::default(),
+            circuit_breaker: CircuitBreakerPolicy::default(),
+            client_options: ClientOptions::default(),
         }
     }
 }
 
 impl Config {
-    pub fn from_env() -> Self {
-        Self {
-            listen_address: env::var("LISTEN_ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            listen_port: env::var("LISTEN_PORT").unwrap_or_else(|_| "3000".to_string()),
-            technitium_url: env::var("TECHNITIUM_URL").expect("Missing TECHNITIUM_URL"),
-            technitium_username: env::var("TECHNITIUM_USERNAME")
-                .expect("Missing TECHNITIUM_USERNAME"),
-            technitium_password: env::var("TECHNITIUM_PASSWORD")
-                .expect("Missing TECHNITIUM_PASSWORD"),
-            zone: env::var("ZONE").expect("Missing ZONE"),
-            domain_filters: env::var("DOMAIN_FILTERS")
-                .ok()
-                .map(|v| v.split(';').map(String::from).collect()),
+    /// Loads the configuration from the environment, and the configuration file if given.
+    ///
+    /// The credentials are returned separately, as they change when rotated while the rest of
+    /// the configuration doesn't.
+    ///
+    /// Returns all problems found in the configuration, rather than only the first one.
+    pub fn load(config_file: Option<PathBuf>) -> Result<(Self, Credentials), Vec<String>> {
+        Self::load_from(config_file, &|name| env::var(name).ok())
+    }
+
+    fn load_from(
+        config_file: Option<PathBuf>,
+        env: &Env,
+    ) -> Result<(Self, Credentials), Vec<String>> {
+        let file = match &config_file {
+            Some(path) => FileConfig::load(path).map_err(|e| vec![e])?,
+            None => FileConfig::default(),
+        };
+        let mut loader = Loader {
+            env,
+            errors: Vec::new(),
+        };
+
+        let technitium_url = match env_or_file(env, "TECHNITIUM_URL") {
+            Ok(url) => url.or(file.technitium.url.clone()),
+            Err(e) => {
+                loader.errors.push(e);
+                None
+            }
+        };
+        if technitium_url.is_none() {
+            loader.errors.push("Missing TECHNITIUM_URL".to_string());
+        }
+        let credentials = Credentials::load(env, &file.technitium)
+            .map_err(|e| loader.errors.push(e))
+            .unwrap_or_default();
+
+        let ttl_limits = TtlLimits {
+            default: loader.parse("DEFAULT_TTL", file.default_ttl.map(|v| v.to_string())),
+            min: loader.parse("MIN_TTL", file.min_ttl.map(|v| v.to_string())),
+            max: loader.parse("MAX_TTL", file.max_ttl.map(|v| v.to_string())),
+        };
+        if let Err(e) = ttl_limits.validate() {
+            loader
+                .errors
+                .push(format!("Invalid MIN_TTL and MAX_TTL: {e}"));
+        }
+        let ttl_policy = TtlPolicy {
+            limits: ttl_limits,
+            rules: loader
+                .parse_list("TTL_RULES", file.ttl_rules)
+                .unwrap_or_default(),
+        };
+        loader.validate_ttl_rules("TTL_RULES", &ttl_policy.rules, ttl_limits);
+
+        // The ZONE environment variable replaces the zones of the configuration file
+        let zones = match env("ZONE") {
+            Some(name) => vec![ZoneFileConfig {
+                name,
+                ..Default::default()
+            }],
+            None => file.zones,
+        };
+        if zones.is_empty() {
+            loader.errors.push("Missing ZONE".to_string());
+        }
+        let zones = zones
+            .into_iter()
+            .map(|zone| loader.zone(zone, &ttl_policy))
+            .collect::<Vec<_>>();
+        for (i, zone) in zones.iter().enumerate() {
+            if zones[..i].iter().any(|z| z.name == zone.name) {
+                loader.errors.push(format!("Duplicate zone {}", zone.name));
+            }
+        }
+
+        let retry_defaults = RetryPolicy::default();
+        let retry_policy = RetryPolicy {
+            max_attempts: loader
+                .parse(
+                    "RETRY_MAX_ATTEMPTS",
+                    file.retry_max_attempts.map(|v| v.to_string()),
+                )
+                .unwrap_or(retry_defaults.max_attempts),
+            initial_backoff: loader
+                .parse(
+                    "RETRY_BACKOFF_MS",
+                    file.retry_backoff_ms.map(|v| v.to_string()),
+                )
+                .map_or(retry_defaults.initial_backoff, Duration::from_millis),
+            max_backoff: loader
+                .parse(
+                    "RETRY_MAX_BACKOFF_MS",
+                    file.retry_max_backoff_ms.map(|v| v.to_string()),
+                )
+                .map_or(retry_defaults.max_backoff, Duration::from_millis),
+        };
+        if retry_policy.max_attempts == 0 {
+            loader
+                .errors
+                .push("Invalid RETRY_MAX_ATTEMPTS: must be at least 1".to_string());
+        }
+        if retry_policy.initial_backoff > retry_policy.max_backoff {
+            loader.errors.push(format!(
+                "Invalid RETRY_BACKOFF_MS and RETRY_MAX_BACKOFF_MS: backoff {:?} is larger than maximum backoff {:?}",
+                retry_policy.initial_backoff, retry_policy.max_backoff
+            ));
+        }
+
+        let breaker_defaults = CircuitBreakerPolicy::default();
+        let circuit_breaker = CircuitBreakerPolicy {
+            failure_threshold: loader
+                .parse(
+                    "CIRCUIT_BREAKER_THRESHOLD",
+                    file.circuit_breaker_threshold.map(|v| v.to_string()),
+                )
+                .unwrap_or(breaker_defaults.failure_threshold),
+            open_duration: loader
+                .parse(
+                    "CIRCUIT_BREAKER_OPEN_SECS",
+                    file.circuit_breaker_open_secs.map(|v| v.to_string()),
+                )
+                .map_or(breaker_defaults.open_duration, Duration::from_secs),
+        };
+        if circuit_breaker.open_duration > CircuitBreakerPolicy::MAX_OPEN_DURATION {
+            loader.errors.push(format!(
+                "Invalid CIRCUIT_BREAKER_OPEN_SECS: must be at most {}",
+                CircuitBreakerPolicy::MAX_OPEN_DURATION.as_secs()
+            ));
+        }
+
+        let client_options = loader.client_options(&file.technitium);
+
+        let listen_port: Option<u16> =
+            loader.parse("LISTEN_PORT", file.listen_port.map(|v| v.to_string()));
+        let config = Self {
+            listen_address: loader
+                .value("LISTEN_ADDRESS", file.listen_address)
+                .unwrap_or_else(|| "0.0.0.0".to_string()),
+            listen_port: listen_port.unwrap_or(3000).to_string(),
+            technitium_url: technitium_url.unwrap_or_default(),
+            watch_credentials: config_file.is_some()
+                || [
+                    "TECHNITIUM_USERNAME_FILE",
+                    "TECHNITIUM_PASSWORD_FILE",
+                    "TECHNITIUM_TOKEN_FILE",
+                ]
+                .iter()
+                .any(|name| env(name).is_some()),
+            config_file,
+            domain_filter: DomainFilter {
+                include: loader
+                    .parse_list("DOMAIN_FILTERS", file.domain_filters)
+                    .unwrap_or_else(|| zones.iter().map(|z| z.name.clone()).collect()),
+                exclude: loader
+                    .parse_list("EXCLUDE_DOMAINS", file.exclude_domains)
+                    .unwrap_or_default(),
+                regex_include: loader.parse("REGEX_DOMAIN_FILTER", file.regex_domain_filter),
+                regex_exclude: loader.parse("REGEX_DOMAIN_EXCLUSION", file.regex_domain_exclusion),
+            },
+            zones,
+            scope_violations: loader
+                .parse("SCOPE_VIOLATIONS", file.scope_violations)
+                .unwrap_or_default(),
+            disabled_records: loader
+                .parse("DISABLED_RECORDS", file.disabled_records)
+                .unwrap_or_default(),
+            protected_records: loader
+                .parse_list("PROTECTED_RECORDS", file.protected_records)
+                .unwrap_or_default(),
+            policy: loader.parse("POLICY", file.policy).unwrap_or_default(),
+            max_changes: loader.parse("MAX_CHANGES", file.max_changes),
+            allow_mass_changes: loader
+                .bool("ALLOW_MASS_CHANGES", file.allow_mass_changes)
+                .unwrap_or(false),
+            dry_run: loader.bool("DRY_RUN", file.dry_run).unwrap_or(false),
+            ttl_policy,
+            startup_timeout: loader
+                .parse(
+                    "STARTUP_TIMEOUT",
+                    file.startup_timeout.map(|v| v.to_string()),
+                )
+                .map(Duration::from_secs),
+            retry_policy,
+            circuit_breaker,
+            client_options,
+        };
+
+        if !loader.errors.is_empty() {
+            return Err(loader.errors);
         }
+        Ok((config, credentials))
     }
 
     pub fn address(&self) -> String {
         format!("{}:{}", self.listen_address, self.listen_port)
     }
+
+    /// Returns the most specific managed zone the name belongs to.
+    pub fn zone(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|z| filter::in_zone(name, &z.name))
+            .max_by_key(|z| z.name.len())
+    }
+
+    /// Returns the TTL policy of the zone the name belongs to, or the global one.
+    pub fn ttl_policy(&self, name: &str) -> &TtlPolicy {
+        self.zone(name).map_or(&self.ttl_policy, |z| &z.ttl_policy)
+    }
+
+    /// Returns true if the record must never be changed by the webhook.
+    pub fn is_protected(&self, name: &str, record_type: &str) -> bool {
+        let zone_records = self
+            .zone(name)
+            .map(|z| z.protected_records.as_slice())
+            .unwrap_or_default();
+        self.protected_records
+            .iter()
+            .chain(zone_records)
+            .any(|s| s.matches(name, record_type))
+    }
+}
+
+/// Looks up an environment variable.
+type Env<'a> = dyn Fn(&str) -> Option<String> + 'a;
+
+/// Reads configuration values from the environment, falling back to the configuration
+/// file, and collects the problems found on the way.
+struct Loader<'a> {
+    env: &'a Env<'a>,
+    errors: Vec<String>,
+}
+
+impl Loader<'_> {
+    fn value(&self, name: &str, file: Option<String>) -> Option<String> {
+        (self.env)(name).or(file)
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, file: Option<String>) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        let value = self.value(name, file)?;
+        value
+            .parse()
+            .map_err(|e| self.errors.push(format!("Invalid {name}: {e}")))
+            .ok()
+    }
+
+    /// Parses a list, which is semicolon-separated in environment variables.
+    fn parse_list<T: FromStr>(&mut self, name: &str, file: Option<Vec<String>>) -> Option<Vec<T>>
+    where
+        T::Err: Display,
+    {
+        let values = match (self.env)(name) {
+            Some(v) => v.split(';').map(String::from).collect(),
+            None => file?,
+        };
+        Some(self.parse_each(name, values))
+    }
+
+    fn parse_each<T: FromStr>(&mut self, name: &str, values: Vec<String>) -> Vec<T>
+    where
+        T::Err: Display,
+    {
+        values
+            .iter()
+            .filter(|v| !v.trim().is_empty())
+            .filter_map(|v| {
+                v.parse()
+                    .map_err(|e| self.errors.push(format!("Invalid {name}: {e}")))
+                    .ok()
+            })
+            .collect()
+    }
+
+    fn bool(&mut self, name: &str, file: Option<bool>) -> Option<bool> {
+        let Some(v) = (self.env)(name) else {
+            return file;
+        };
+        match v.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(true),
+            "false" | "0" | "no" | "" => Some(false),
+            _ => {
+                self.errors
+                    .push(format!("Invalid {name}: expected true or false, got {v}"));
+                None
+            }
+        }
+    }
+
+    /// Reads the file the setting `name` points to.
+    fn read_file(&mut self, name: &str, path: &str) -> Option<Vec<u8>> {
+        fs::read(path)
+            .map_err(|e| {
+                self.errors
+                    .push(format!("Failed to read {name} {path}: {e}"))
+            })
+            .ok()
+    }
+
+    fn client_options(&mut self, file: &TechnitiumFileConfig) -> ClientOptions {
+        let defaults = ClientOptions::default();
+
+        let mut ca_certificates = Vec::new();
+        if let Some(path) = self.value("TECHNITIUM_CA_CERT", file.ca_cert.clone())
+            && let Some(pem) = self.read_file("TECHNITIUM_CA_CERT", &path)
+        {
+            match Certificate::from_pem_bundle(&pem) {
+                Ok(certificates) if certificates.is_empty() => self.errors.push(format!(
+                    "Invalid TECHNITIUM_CA_CERT: no certificates in {path}"
+                )),
+                Ok(certificates) => ca_certificates = certificates,
+                Err(e) => self.errors.push(format!("Invalid TECHNITIUM_CA_CERT: {e}")),
+            }
+        }
+
+        let identity = match (
+            self.value("TECHNITIUM_CLIENT_CERT", file.client_cert.clone()),
+            self.value("TECHNITIUM_CLIENT_KEY", file.client_key.clone()),
+        ) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = self.read_file("TECHNITIUM_CLIENT_CERT", &cert_path);
+                let key = self.read_file("TECHNITIUM_CLIENT_KEY", &key_path);
+                match (cert, key) {
+                    (Some(cert), Some(key)) => Identity::from_pem(&[cert, key].join(&b'\n'))
+                        .map_err(|e| {
+                            self.errors.push(format!(
+                                "Invalid TECHNITIUM_CLIENT_CERT and TECHNITIUM_CLIENT_KEY: {e}"
+                            ))
+                        })
+                        .ok(),
+                    _ => None,
+                }
+            }
+            (None, None) => None,
+            _ => {
+                self.errors.push(
+                    "TECHNITIUM_CLIENT_CERT and TECHNITIUM_CLIENT_KEY must be set together"
+                        .to_string(),
+                );
+                None
+            }
+        };
+
+        let proxy = self
+            .value("TECHNITIUM_PROXY", file.proxy.clone())
+            .and_then(|url| {
+                Proxy::all(url.as_str())
+                    .map_err(|e| self.errors.push(format!("Invalid TECHNITIUM_PROXY: {e}")))
+                    .ok()
+            });
+
+        let timeout = self
+            .parse("TECHNITIUM_TIMEOUT", file.timeout.map(|v| v.to_string()))
+            .map_or(defaults.timeout, Duration::from_secs);
+        if timeout.is_zero() {
+            self.errors
+                .push("Invalid TECHNITIUM_TIMEOUT: must be at least 1".to_string());
+        }
+
+        ClientOptions {
+            timeout,
+            ca_certificates,
+            identity,
+            accept_invalid_certs: self
+                .bool("TECHNITIUM_TLS_SKIP_VERIFY", file.tls_skip_verify)
+                .unwrap_or(false),
+            proxy,
+        }
+    }
+
+    fn validate_ttl_rules(&mut self, name: &str, rules: &[TtlRule], limits: TtlLimits) {
+        // Invalid limits are already reported, and would fail every rule relying on them
+        if limits.validate().is_err() {
+            return;
+        }
+        for rule in rules {
+            if let Err(e) = rule.validate(limits) {
+                self.errors.push(format!("Invalid {name}: {e}"));
+            }
+        }
+    }
+
+    fn zone(&mut self, zone: ZoneFileConfig, global: &TtlPolicy) -> Zone {
+        // Technitium stores and lists zones in their ASCII form
+        let name = to_ascii(&zone.name).unwrap_or_else(|e| {
+            self.errors.push(format!("Invalid zone: {e}"));
+            zone.name.trim().trim_end_matches('.').to_string()
+        });
+        let limits = TtlLimits {
+            default: zone.default_ttl,
+            min: zone.min_ttl,
+            max: zone.max_ttl,
+        }
+        .or(global.limits);
+        if let Err(e) = limits.validate() {
+            self.errors
+                .push(format!("Invalid TTL limits of zone {name}: {e}"));
+        }
+        let mut rules = self.parse_each(&format!("TTL rule of zone {name}"), zone.ttl_rules);
+        self.validate_ttl_rules(&format!("TTL rule of zone {name}"), &rules, limits);
+        // The global rules were already checked against the global limits
+        if limits != global.limits {
+            self.validate_ttl_rules(&format!("TTL_RULES in zone {name}"), &global.rules, limits);
+        }
+        rules.extend(global.rules.iter().cloned());
+        Zone {
+            ttl_policy: TtlPolicy { limits, rules },
+            protected_records: self.parse_each(
+                &format!("protected record of zone {name}"),
+                zone.protected_records,
+            ),
+            name,
+        }
+    }
+}
+
+/// Reads the value from the file named by `{name}_FILE` if set, and from the `name`
+/// environment variable otherwise.
+fn env_or_file(env: &Env, name: &str) -> Result<Option<String>, String> {
+    value_or_file(name, env(name), env(&format!("{name}_FILE")))
+}
+
+fn value_or_file(
+    name: &str,
+    value: Option<String>,
+    file: Option<String>,
+) -> Result<Option<String>, String> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(format!("{name} and {name}_FILE can't both be set")),
+        (None, Some(path)) => fs::read_to_string(&path)
+            .map(|v| Some(v.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|e| format!("Failed to read {name}_FILE {path}: {e}")),
+        (value, None) => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_limit_parsing() {
+        assert_eq!("25".parse(), Ok(ChangeLimit::Count(25)));
+        assert_eq!("12.5%".parse(), Ok(ChangeLimit::Percent(12.5)));
+        assert!("150%".parse::<ChangeLimit>().is_err());
+        assert!("many".parse::<ChangeLimit>().is_err());
+    }
+
+    #[test]
+    fn test_change_limit() {
+        assert_eq!(ChangeLimit::Count(25).limit(10), 25);
+        assert_eq!(ChangeLimit::Percent(50.0).limit(9), 4);
+        assert_eq!(ChangeLimit::Percent(50.0).limit(0), 0);
+    }
+
+    #[test]
+    fn test_value_or_file() {
+        let path = env::temp_dir().join(format!("technitium-webhook-test-{}", std::process::id()));
+        fs::write(&path, "secret\n").unwrap();
+        let file = Some(path.to_string_lossy().into_owned());
+
+        assert_eq!(
+            value_or_file("PASSWORD", None, file.clone()),
+            Ok(Some("secret".to_string()))
+        );
+        assert_eq!(
+            value_or_file("PASSWORD", Some("value".to_string()), None),
+            Ok(Some("value".to_string()))
+        );
+        assert_eq!(value_or_file("PASSWORD", None, None), Ok(None));
+        assert!(value_or_file("PASSWORD", Some("value".to_string()), file).is_err());
+        assert!(value_or_file("PASSWORD", None, Some("/nonexistent".to_string())).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_load_from_env() {
+        let (config, credentials) = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "http://localhost:5380"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", "example.com"),
+                ("POLICY", "upsert-only"),
+                ("DRY_RUN", "yes"),
+                ("RETRY_MAX_ATTEMPTS", "5"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.technitium_url, "http://localhost:5380");
+        assert_eq!(credentials, Credentials::Token("secret".to_string()));
+        assert_eq!(config.zones.len(), 1);
+        assert_eq!(config.zones[0].name, "example.com");
+        assert_eq!(config.domain_filter.include, vec!["example.com"]);
+        assert_eq!(config.policy, Policy::UpsertOnly);
+        assert!(config.dry_run);
+        assert!(!config.watch_credentials);
+        assert_eq!(
+            config.retry_policy,
+            RetryPolicy {
+                max_attempts: 5,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_from_file_with_env_overrides() {
+        let path = env::temp_dir().join(format!("technitium-webhook-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+            policy = "create-only"
+            max_ttl = 3600
+            protected_records = ["example.com"]
+
+            [technitium]
+            url = "http://localhost:5380"
+            username = "admin"
+            password = "admin"
+
+            [[zones]]
+            name = "example.com"
+
+            [[zones]]
+            name = "dyn.example.com."
+            default_ttl = 60
+            ttl_rules = ["static.dyn.example.com=default:600"]
+            protected_records = ["*.vpn.dyn.example.com:A"]
+            "#,
+        )
+        .unwrap();
+
+        let (config, credentials) = Config::load_from(
+            Some(path.clone()),
+            &env(&[("POLICY", "sync"), ("TECHNITIUM_PASSWORD", "rotated")]),
+        )
+        .unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(config.policy, Policy::Sync);
+        assert_eq!(
+            credentials,
+            Credentials::Password {
+                username: "admin".to_string(),
+                password: "rotated".to_string(),
+            }
+        );
+        assert!(config.watch_credentials);
+        assert_eq!(
+            config.domain_filter.include,
+            vec!["example.com", "dyn.example.com"]
+        );
+
+        assert_eq!(config.zone("www.example.com").unwrap().name, "example.com");
+        assert_eq!(
+            config.zone("a.dyn.example.com").unwrap().name,
+            "dyn.example.com"
+        );
+        assert!(config.zone("example.org").is_none());
+
+        assert_eq!(
+            config
+                .ttl_policy("www.example.com")
+                .apply("www.example.com", "A", None),
+            None
+        );
+        assert_eq!(
+            config
+                .ttl_policy("a.dyn.example.com")
+                .apply("a.dyn.example.com", "A", None),
+            Some(60)
+        );
+        assert_eq!(
+            config
+                .ttl_policy("static.dyn.example.com")
+                .apply("static.dyn.example.com", "A", None),
+            Some(600)
+        );
+        assert_eq!(
+            config
+                .ttl_policy("a.dyn.example.com")
+                .apply("a.dyn.example.com", "A", Some(7200)),
+            Some(3600)
+        );
+
+        assert!(config.is_protected("example.com", "A"));
+        assert!(config.is_protected("a.vpn.dyn.example.com", "A"));
+        assert!(!config.is_protected("a.vpn.dyn.example.com", "TXT"));
+    }
+
+    #[test]
+    fn test_load_reports_all_errors() {
+        let errors = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_USERNAME", "admin"),
+                ("POLICY", "everything"),
+                ("MAX_CHANGES", "lots"),
+                ("MIN_TTL", "600"),
+                ("MAX_TTL", "60"),
+                ("DRY_RUN", "maybe"),
+                ("TTL_RULES", "example.com=default:60;broken"),
+                ("RETRY_MAX_ATTEMPTS", "0"),
+            ]),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            errors,
+            [
+                "Missing TECHNITIUM_URL",
+                "Missing TECHNITIUM_PASSWORD",
+                "Invalid MIN_TTL and MAX_TTL: minimum TTL 600 is larger than maximum TTL 60",
+                "Invalid TTL_RULES: invalid TTL rule: broken",
+                "Missing ZONE",
+                "Invalid RETRY_MAX_ATTEMPTS: must be at least 1",
+                "Invalid POLICY: unknown policy: everything",
+                "Invalid MAX_CHANGES: invalid change count: lots",
+                "Invalid DRY_RUN: expected true or false, got maybe",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_ttl_rules_conflicting_with_limits() {
+        let path = env::temp_dir().join(format!(
+            "technitium-webhook-{}-ttl.toml",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            r#"
+            max_ttl = 300
+            ttl_rules = ["*.dyn.example.com:A=min:600", "*.static.example.com=max:60"]
+
+            [technitium]
+            url = "http://localhost:5380"
+            token = "secret"
+
+            [[zones]]
+            name = "example.com"
+            min_ttl = 120
+            max_ttl = 3600
+            ttl_rules = ["*.vpn.example.com=max:60"]
+            "#,
+        )
+        .unwrap();
+
+        let errors = Config::load_from(Some(path.clone()), &env(&[])).unwrap_err();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            errors,
+            [
+                "Invalid TTL_RULES: TTL rule *.dyn.example.com:A: minimum TTL 600 is larger than maximum TTL 300",
+                "Invalid TTL rule of zone example.com: TTL rule *.vpn.example.com: minimum TTL 120 is larger than maximum TTL 60",
+                "Invalid TTL_RULES in zone example.com: TTL rule *.static.example.com: minimum TTL 120 is larger than maximum TTL 60",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_zones() {
+        let errors = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "http://localhost:5380"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", "bad zone.com"),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Invalid zone"));
+    }
+
+    #[test]
+    fn test_load_rejects_huge_circuit_breaker_open_duration() {
+        let errors = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "http://localhost:5380"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", "example.com"),
+                ("CIRCUIT_BREAKER_OPEN_SECS", "18446744073709551615"),
+            ]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            ["Invalid CIRCUIT_BREAKER_OPEN_SECS: must be at most 86400"]
+        );
+    }
+
+    #[test]
+    fn test_load_stores_zones_in_ascii_form() {
+        let (config, _) = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "http://localhost:5380"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", " Bücher.example. "),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.zones[0].name, "xn--bcher-kva.example");
+        assert!(config.domain_filter.matches("www.bücher.example"));
+    }
+
+    #[test]
+    fn test_load_client_options() {
+        let (config, _) = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "https://localhost:53443"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", "example.com"),
+                ("TECHNITIUM_TIMEOUT", "30"),
+                ("TECHNITIUM_TLS_SKIP_VERIFY", "true"),
+                ("TECHNITIUM_PROXY", "http://proxy.local:3128"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.client_options.timeout, Duration::from_secs(30));
+        assert!(config.client_options.accept_invalid_certs);
+        assert!(config.client_options.proxy.is_some());
+        assert!(config.client_options.ca_certificates.is_empty());
+        assert!(config.client_options.identity.is_none());
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_client_options() {
+        let path = env::temp_dir().join(format!("technitium-webhook-{}.pem", std::process::id()));
+        fs::write(&path, "not a certificate").unwrap();
+        let errors = Config::load_from(
+            None,
+            &env(&[
+                ("TECHNITIUM_URL", "https://localhost:53443"),
+                ("TECHNITIUM_TOKEN", "secret"),
+                ("ZONE", "example.com"),
+                ("TECHNITIUM_CA_CERT", path.to_str().unwrap()),
+                ("TECHNITIUM_CLIENT_CERT", "/nonexistent/client.pem"),
+                ("TECHNITIUM_TIMEOUT", "0"),
+            ]),
+        )
+        .unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert!(errors[0].starts_with("Invalid TECHNITIUM_CA_CERT: no certificates"));
+        assert_eq!(
+            errors[1],
+            "TECHNITIUM_CLIENT_CERT and TECHNITIUM_CLIENT_KEY must be set together"
+        );
+        assert_eq!(errors[2], "Invalid TECHNITIUM_TIMEOUT: must be at least 1");
+    }
 }
@@ -0,0 +1,123 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Settings read from the optional TOML configuration file.
+///
+/// Every setting is optional and named like its environment variable in lowercase,
+/// which takes precedence over it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub listen_address: Option<String>,
+    pub listen_port: Option<u16>,
+    pub technitium: TechnitiumFileConfig,
+    pub zones: Vec<ZoneFileConfig>,
+    pub domain_filters: Option<Vec<String>>,
+    pub exclude_domains: Option<Vec<String>>,
+    pub regex_domain_filter: Option<String>,
+    pub regex_domain_exclusion: Option<String>,
+    pub scope_violations: Option<String>,
+    pub disabled_records: Option<String>,
+    pub protected_records: Option<Vec<String>>,
+    pub policy: Option<String>,
+    pub max_changes: Option<String>,
+    pub allow_mass_changes: Option<bool>,
+    pub dry_run: Option<bool>,
+    pub default_ttl: Option<u32>,
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
+    pub ttl_rules: Option<Vec<String>>,
+    pub startup_timeout: Option<u64>,
+    pub retry_max_attempts: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub retry_max_backoff_ms: Option<u64>,
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_open_secs: Option<u64>,
+}
+
+/// The `[technitium]` table, with the `TECHNITIUM_*` settings.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TechnitiumFileConfig {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub timeout: Option<u64>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub tls_skip_verify: Option<bool>,
+    pub proxy: Option<String>,
+}
+
+/// A `[[zones]]` entry, with the settings that only apply to the records of that zone.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneFileConfig {
+    pub name: String,
+    #[serde(default)]
+    pub default_ttl: Option<u32>,
+    #[serde(default)]
+    pub min_ttl: Option<u32>,
+    #[serde(default)]
+    pub max_ttl: Option<u32>,
+    #[serde(default)]
+    pub ttl_rules: Vec<String>,
+    #[serde(default)]
+    pub protected_records: Vec<String>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read configuration file {}: {e}", path.display()))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid configuration file {}: {e}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: FileConfig = toml::from_str(
+            r#"
+            policy = "upsert-only"
+            dry_run = true
+            min_ttl = 60
+
+            [technitium]
+            url = "http://localhost:5380"
+            token = "secret"
+
+            [[zones]]
+            name = "example.com"
+
+            [[zones]]
+            name = "example.org"
+            default_ttl = 300
+            ttl_rules = ["*.dyn.example.org=default:60"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.policy.as_deref(), Some("upsert-only"));
+        assert_eq!(config.dry_run, Some(true));
+        assert_eq!(config.min_ttl, Some(60));
+        assert_eq!(config.technitium.token.as_deref(), Some("secret"));
+        assert_eq!(config.zones.len(), 2);
+        assert_eq!(config.zones[1].default_ttl, Some(300));
+        assert_eq!(config.zones[1].ttl_rules.len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_settings_are_rejected() {
+        assert!(toml::from_str::<FileConfig>("dryrun = true").is_err());
+        assert!(toml::from_str::<FileConfig>("[[zones]]\nname = \"a\"\nttl = 1").is_err());
+        assert!(toml::from_str::<FileConfig>("[[zones]]\ndefault_ttl = 1").is_err());
+    }
+}
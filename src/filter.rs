@@ -0,0 +1,167 @@
+use crate::models::Filters;
+use crate::normalize::normalize_name;
+use regex::Regex;
+
+/// The domain filter negotiated with external-dns, also enforced on the webhook side.
+///
+/// Follows the external-dns semantics: a name matches an include or exclude
+/// entry if it is equal to it or a subdomain of it, and entries starting with
+/// a dot only match subdomains. When either regex is set, the include and
+/// exclude lists are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct DomainFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub regex_include: Option<Regex>,
+    pub regex_exclude: Option<Regex>,
+}
+
+impl DomainFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+
+        if self.regex_include.is_some() || self.regex_exclude.is_some() {
+            return self
+                .regex_include
+                .as_ref()
+                .is_none_or(|r| r.is_match(&name))
+                && !self
+                    .regex_exclude
+                    .as_ref()
+                    .is_some_and(|r| r.is_match(&name));
+        }
+
+        (self.include.is_empty() || self.include.iter().any(|f| match_domain(f, &name)))
+            && !self.exclude.iter().any(|f| match_domain(f, &name))
+    }
+}
+
+impl From<&DomainFilter> for Filters {
+    fn from(filter: &DomainFilter) -> Self {
+        // external-dns rejects a filter with both domain lists and a regex, and the lists are
+        // ignored anyway when a regex is set
+        if filter.regex_include.is_some() || filter.regex_exclude.is_some() {
+            return Filters {
+                regex_include: filter
+                    .regex_include
+                    .as_ref()
+                    .map(|r| r.as_str().to_string())
+                    .unwrap_or_default(),
+                regex_exclude: filter
+                    .regex_exclude
+                    .as_ref()
+                    .map(|r| r.as_str().to_string())
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+        }
+        Filters {
+            filters: filter.include.clone(),
+            include: filter.include.clone(),
+            exclude: filter.exclude.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns true if the name is the zone apex or a name within the zone.
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    let name = normalize_name(name);
+    let zone = normalize_name(zone);
+    name == zone || name.ends_with(&format!(".{zone}"))
+}
+
+fn match_domain(filter: &str, name: &str) -> bool {
+    if filter.trim().is_empty() {
+        return false;
+    }
+    if let Some(filter) = filter.trim().strip_prefix('.') {
+        return name.ends_with(&format!(".{}", normalize_name(filter)));
+    }
+    let filter = normalize_name(filter);
+    name == filter || name.ends_with(&format!(".{filter}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = DomainFilter {
+            include: vec!["example.com".to_string(), ".example.org".to_string()],
+            exclude: vec!["internal.example.com".to_string()],
+            ..Default::default()
+        };
+
+        assert!(filter.matches("example.com"));
+        assert!(filter.matches("www.Example.com."));
+        assert!(!filter.matches("badexample.com"));
+        assert!(!filter.matches("example.org"));
+        assert!(filter.matches("www.example.org"));
+        assert!(!filter.matches("internal.example.com"));
+        assert!(!filter.matches("db.internal.example.com"));
+    }
+
+    #[test]
+    fn test_in_zone() {
+        assert!(in_zone("example.com", "example.com"));
+        assert!(in_zone("www.EXAMPLE.com.", "example.com."));
+        assert!(!in_zone("badexample.com", "example.com"));
+        assert!(!in_zone("example.org", "example.com"));
+        assert!(in_zone("www.bücher.example", "xn--bcher-kva.example"));
+    }
+
+    #[test]
+    fn test_empty_filter_matches_everything() {
+        assert!(DomainFilter::default().matches("example.com"));
+    }
+
+    #[test]
+    fn test_regex_takes_precedence() {
+        let filter = DomainFilter {
+            include: vec!["example.org".to_string()],
+            regex_include: Some(Regex::new(r"^[a-z]+\.example\.com$").unwrap()),
+            regex_exclude: Some(Regex::new(r"^internal\.").unwrap()),
+            ..Default::default()
+        };
+
+        assert!(filter.matches("www.example.com"));
+        assert!(!filter.matches("internal.example.com"));
+        assert!(!filter.matches("www.example.org"));
+    }
+
+    #[test]
+    fn test_filters_serialization() {
+        let filter = DomainFilter {
+            include: vec!["example.com".to_string()],
+            exclude: vec!["internal.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let serialized = serde_json::to_value(Filters::from(&filter)).unwrap();
+        let expected = serde_json::json!({
+            "filters": ["example.com"],
+            "include": ["example.com"],
+            "exclude": ["internal.example.com"],
+        });
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn test_filters_serialization_with_regex() {
+        let filter = DomainFilter {
+            include: vec!["example.com".to_string()],
+            exclude: vec!["internal.example.com".to_string()],
+            regex_exclude: Some(Regex::new(r"^test\.").unwrap()),
+            ..Default::default()
+        };
+
+        let serialized = serde_json::to_value(Filters::from(&filter)).unwrap();
+        let expected = serde_json::json!({
+            "filters": [],
+            "regexExclude": r"^test\.",
+        });
+        assert_eq!(serialized, expected);
+    }
+}
@@ -1,5 +1,10 @@
+use crate::app::{ScopeViolation, StartupState};
+use crate::config::{ChangeLimit, Config, DisabledRecordsPolicy, Policy, ScopeViolations};
+use crate::metrics::Metrics;
 use crate::models::{Changes, Endpoint, Filters};
-use crate::technitium::RecordData;
+use crate::normalize::{group_endpoints, normalize_endpoint, validate_endpoint};
+use crate::plan::{self, record_target};
+use crate::technitium::RecordInfo;
 use crate::{AppError, AppState, technitium};
 use axum::extract::State;
 use axum::http::{HeaderValue, header};
@@ -11,11 +16,26 @@ use std::sync::Arc;
 use tracing::{debug, info, warn};
 
 /// Health check endpoint
-pub async fn health_check(
-    State(app_state): State<Arc<AppState>>,
-) -> Result<impl IntoResponse, AppError> {
-    app_state.ensure_ready().await?;
-    Ok(StatusCode::OK)
+///
+/// Reports the startup progress, and is only successful once the webhook is ready.
+pub async fn health_check(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    let status = app_state.startup.borrow().clone();
+    let code = match status.state {
+        StartupState::Ready => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (code, Json(status))
+}
+
+/// Metrics endpoint in the Prometheus text format
+pub async fn metrics(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )],
+        app_state.metrics.render(),
+    )
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -49,13 +69,7 @@ pub async fn negotiate_domain_filter(
 ) -> Result<impl IntoResponse, AppError> {
     app_state.ensure_ready().await?;
 
-    let filters = app_state
-        .config
-        .domain_filters
-        .clone()
-        .unwrap_or_else(|| vec![app_state.config.zone.clone()]);
-
-    Ok(ExtDnsJson(Filters { filters }))
+    Ok(ExtDnsJson(Filters::from(&app_state.config.domain_filter)))
 }
 
 /// Returns the current DNS records.
@@ -66,51 +80,86 @@ pub async fn get_records(
 ) -> Result<impl IntoResponse, AppError> {
     app_state.ensure_ready().await?;
 
+    let endpoints = fetch_endpoints(&app_state).await?;
+
+    Ok(ExtDnsJson(endpoints))
+}
+
+/// Checks that the endpoint is within the managed zone and matches the domain filter.
+fn scope_violation(config: &Config, ep: &Endpoint) -> Option<ScopeViolation> {
+    let reason = if config.zone(&ep.dns_name).is_none() {
+        let zones = config.zones.iter().map(|z| z.name.as_str());
+        format!(
+            "outside of the managed zones {}",
+            zones.collect::<Vec<_>>().join(", ")
+        )
+    } else if !config.domain_filter.matches(&ep.dns_name) {
+        "does not match the domain filter".to_string()
+    } else {
+        return None;
+    };
+    Some(ScopeViolation {
+        dns_name: ep.dns_name.clone(),
+        record_type: ep.record_type.clone(),
+        reason,
+    })
+}
+
+/// Fetches all records of the managed zones.
+async fn fetch_records(app_state: &AppState) -> Result<Vec<RecordInfo>, AppError> {
+    let mut records = Vec::new();
+    for zone in &app_state.config.zones {
+        let ret = app_state
+            .with_client(|client| async move {
+                client
+                    .get_records(technitium::GetRecordsPayload {
+                        domain: zone.name.clone(),
+                        list_zone: Some(true),
+                        ..Default::default()
+                    })
+                    .await
+            })
+            .await?;
+        records.extend(ret.records);
+    }
+    Ok(records)
+}
+
+/// Fetches the records of the managed zone as external-dns endpoints.
+pub async fn fetch_endpoints(app_state: &AppState) -> Result<Vec<Endpoint>, AppError> {
     debug!("Fetching DNS records");
 
-    let ret = app_state
-        .client
-        .read()
-        .await
-        .get_records(technitium::GetRecordsPayload {
-            domain: app_state.config.zone.clone(),
-            list_zone: Some(true),
-            ..Default::default()
-        })
-        .await?;
+    let endpoints = to_endpoints(&app_state.config, &fetch_records(app_state).await?);
+
+    debug!("Found {} endpoints", endpoints.len());
+
+    Ok(endpoints)
+}
 
+/// Converts the records to the endpoints reported to external-dns.
+fn to_endpoints(config: &Config, records: &[RecordInfo]) -> Vec<Endpoint> {
     let mut endpoints = Vec::new();
-    for ri in ret.records {
-        let mut ep = Endpoint {
-            dns_name: ri.name,
+    for ri in records {
+        if ri.disabled && config.disabled_records != DisabledRecordsPolicy::Show {
+            debug!("Skipping disabled record {} ({:?})", ri.name, ri.data);
+            continue;
+        }
+        if !config.domain_filter.matches(&ri.name) {
+            debug!("Skipping record {} outside of the domain filter", ri.name);
+            continue;
+        }
+        let Some((record_type, target)) = record_target(&ri.data) else {
+            continue;
+        };
+        endpoints.push(normalize_endpoint(Endpoint {
+            dns_name: ri.name.clone(),
+            targets: vec![target],
+            record_type: record_type.to_string(),
             record_ttl: Some(ri.ttl),
             ..Default::default()
-        };
-        match ri.data {
-            RecordData::A(data) => {
-                ep.record_type = "A".to_string();
-                ep.targets = vec![data.ip_address.to_string()];
-            }
-            RecordData::AAAA(data) => {
-                ep.record_type = "AAAA".to_string();
-                ep.targets = vec![data.ip_address.to_string()];
-            }
-            RecordData::CNAME(data) => {
-                ep.record_type = "CNAME".to_string();
-                ep.targets = vec![data.cname.to_string()];
-            }
-            RecordData::TXT(data) => {
-                ep.record_type = "TXT".to_string();
-                ep.targets = vec![data.text.to_string()];
-            }
-            RecordData::Other { .. } => continue,
-        }
-        endpoints.push(ep);
+        }));
     }
-
-    debug!("Found {} endpoints", endpoints.len());
-
-    Ok(ExtDnsJson(endpoints))
+    group_endpoints(endpoints)
 }
 
 /// Executes the AdjustEndpoints method.
@@ -123,7 +172,39 @@ pub async fn adjust_endpoints(
 ) -> Result<impl IntoResponse, AppError> {
     app_state.ensure_ready().await?;
 
-    // We don't do any endpoint adjustment
+    let endpoints = endpoints
+        .into_iter()
+        .map(normalize_endpoint)
+        .map(|mut ep| {
+            ep.record_ttl = app_state.config.ttl_policy(&ep.dns_name).apply(
+                &ep.dns_name,
+                &ep.record_type,
+                ep.record_ttl,
+            );
+            ep
+        })
+        .filter(|ep| match validate_endpoint(ep) {
+            Ok(()) => true,
+            Err(reason) => {
+                warn!(
+                    "Ignoring desired state of {} ({}): {}",
+                    ep.dns_name, ep.record_type, reason
+                );
+                false
+            }
+        })
+        .filter(|ep| {
+            if app_state.config.is_protected(&ep.dns_name, &ep.record_type) {
+                warn!(
+                    "Ignoring desired state of protected record {} ({})",
+                    ep.dns_name, ep.record_type
+                );
+                return false;
+            }
+            true
+        })
+        .collect::<Vec<_>>();
+
     Ok(ExtDnsJson(endpoints))
 }
 
@@ -137,82 +218,730 @@ pub async fn apply_record(
 ) -> Result<impl IntoResponse, AppError> {
     app_state.ensure_ready().await?;
 
+    apply_changes(&app_state, changes).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Applies the changes to the DNS provider, after applying the policy, scope and
+/// protection rules.
+pub async fn apply_changes(app_state: &AppState, mut changes: Changes) -> Result<(), AppError> {
+    let allowed = |action: &str, ep: &Endpoint| {
+        if scope_violation(&app_state.config, ep).is_some() {
+            return false;
+        }
+        // Only the invalid endpoints are skipped, so they don't block unrelated changes
+        if let Err(reason) = validate_endpoint(ep) {
+            warn!(
+                "Refusing to {} {} ({}): {}",
+                action, ep.dns_name, ep.record_type, reason
+            );
+            return false;
+        }
+        if app_state.config.is_protected(&ep.dns_name, &ep.record_type) {
+            warn!(
+                "Refusing to {} protected record {} ({}) with targets {:?}",
+                action, ep.dns_name, ep.record_type, ep.targets
+            );
+            return false;
+        }
+        true
+    };
+
+    let policy = app_state.config.policy;
+    if policy != Policy::Sync {
+        let dropped = changes.delete.take().unwrap_or_default();
+        for ep in &dropped {
+            info!(
+                "Dropping deletion of {} ({}) due to {:?} policy",
+                ep.dns_name, ep.record_type, policy
+            );
+        }
+        Metrics::inc(&app_state.metrics.dropped_deletes, dropped.len() as u64);
+    }
+    if policy == Policy::CreateOnly {
+        changes.update_old = None;
+        let dropped = changes.update_new.take().unwrap_or_default();
+        for ep in &dropped {
+            info!(
+                "Dropping update of {} ({}) due to {:?} policy",
+                ep.dns_name, ep.record_type, policy
+            );
+        }
+        Metrics::inc(&app_state.metrics.dropped_updates, dropped.len() as u64);
+    }
+
+    let violations = [
+        &changes.create,
+        &changes.update_old,
+        &changes.update_new,
+        &changes.delete,
+    ]
+    .into_iter()
+    .flatten()
+    .flatten()
+    .filter_map(|ep| scope_violation(&app_state.config, ep))
+    .collect::<Vec<_>>();
+    for violation in &violations {
+        warn!(
+            "Change to {} ({}) is out of scope: {}",
+            violation.dns_name, violation.record_type, violation.reason
+        );
+    }
+    if !violations.is_empty() && app_state.config.scope_violations == ScopeViolations::Reject {
+        return Err(AppError::OutOfScope(violations));
+    }
+
     let deletions = changes
         .delete
         .unwrap_or_default()
         .into_iter()
-        .chain(changes.update_old.unwrap_or_default().into_iter())
+        .filter(|ep| allowed("delete", ep))
         .collect::<Vec<_>>();
+    let updates = plan::pair_updates(
+        changes.update_old.unwrap_or_default(),
+        changes.update_new.unwrap_or_default(),
+    )
+    .into_iter()
+    .filter(|u| allowed("update", &u.old) && allowed("update", &u.new))
+    .collect::<Vec<_>>();
     let additions = changes
         .create
         .unwrap_or_default()
         .into_iter()
-        .chain(changes.update_new.unwrap_or_default().into_iter())
+        .filter(|ep| allowed("create", ep))
         .collect::<Vec<_>>();
 
-    if deletions.is_empty() && additions.is_empty() {
+    if deletions.is_empty() && updates.is_empty() && additions.is_empty() {
         info!("All records already up to date, skipping apply");
-        return Ok(StatusCode::NO_CONTENT);
-    }
-
-    for ep in deletions {
-        for target in ep.targets {
-            let data = match ep.record_type.as_str() {
-                "A" => technitium::RecordAData { ip_address: target }.into(),
-                "AAAA" => technitium::RecordAAAAData { ip_address: target }.into(),
-                "CNAME" => technitium::RecordCNAMEData { cname: target }.into(),
-                "TXT" => technitium::RecordTXTData { text: target }.into(),
-                _ => {
-                    warn!(
-                        "Skipping deletion of {} with invalid record type of {}",
-                        ep.dns_name, ep.record_type
-                    );
-                    continue;
-                }
+        return Ok(());
+    }
+
+    // The current records are needed to detect conflicts, to re-enable disabled records, and
+    // to delete or update records with the exact data they are stored with
+    let records = fetch_records(app_state).await?;
+
+    if let Some(max_changes) = app_state.config.max_changes {
+        let changes = deletions
+            .iter()
+            .map(|ep| ep.targets.len())
+            .chain(updates.iter().map(plan::Update::changed_records))
+            .sum::<usize>();
+        if app_state.config.allow_mass_changes {
+            debug!("Mass change guard is overridden, not checking {changes} changes");
+        } else {
+            let current = match max_changes {
+                ChangeLimit::Count(_) => 0,
+                ChangeLimit::Percent(_) => to_endpoints(&app_state.config, &records)
+                    .iter()
+                    .map(|ep| ep.targets.len())
+                    .sum(),
             };
-            info!("Deleting record {} with data {:?}", ep.dns_name, data);
-            app_state
-                .client
-                .read()
+            let limit = max_changes.limit(current);
+            if changes > limit {
+                warn!(
+                    "Refusing to apply batch that deletes or updates {changes} records, limit is {limit}"
+                );
+                return Err(AppError::ChangeLimitExceeded { changes, limit });
+            }
+        }
+    }
+
+    let operations = plan::plan(
+        deletions,
+        updates,
+        additions,
+        &records,
+        app_state.config.disabled_records,
+    )
+    .map_err(|conflicts| {
+        for conflict in &conflicts {
+            warn!("Refusing to apply conflicting change: {}", conflict);
+        }
+        AppError::RecordConflict(conflicts)
+    })?;
+
+    if app_state.config.dry_run {
+        for operation in operations {
+            info!("Dry run, not performing {:?}", operation);
+        }
+        return Ok(());
+    }
+
+    for operation in &operations {
+        app_state
+            .with_client(|client| async move { operation.clone().execute(&client).await })
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::StartupStatus;
+    use crate::config::{Credentials, Zone};
+    use crate::filter::DomainFilter;
+    use serde_json::json;
+
+    /// A configuration managing the `example.com` zone on the mock server.
+    fn config(server: &mockito::Server) -> Config {
+        Config {
+            technitium_url: server.url(),
+            zones: vec![Zone {
+                name: "example.com".to_string(),
+                ..Default::default()
+            }],
+            domain_filter: DomainFilter {
+                include: vec!["example.com".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn ready(config: Config) -> AppState {
+        let app_state = AppState::new(config, Credentials::Token("token".to_string())).unwrap();
+        app_state.startup.send_replace(StartupStatus {
+            state: StartupState::Ready,
+            ..Default::default()
+        });
+        app_state
+    }
+
+    fn app_state(server: &mockito::Server, disabled_records: DisabledRecordsPolicy) -> AppState {
+        ready(Config {
+            disabled_records,
+            ..config(server)
+        })
+    }
+
+    fn endpoint(dns_name: &str, targets: &[&str]) -> Endpoint {
+        Endpoint {
+            dns_name: dns_name.to_string(),
+            record_type: "A".to_string(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Mocks adding, updating or deleting the records of a name, expected `hits` times.
+    fn mock_change(
+        server: &mut mockito::Server,
+        action: &str,
+        dns_name: &str,
+        hits: usize,
+    ) -> mockito::Mock {
+        let body = match action {
+            "add" => applied("addedRecord"),
+            "update" => applied("updatedRecord"),
+            _ => json!({"status": "ok", "response": {}}).to_string(),
+        };
+        server
+            .mock("POST", format!("/api/zones/records/{action}").as_str())
+            .match_body(mockito::Matcher::UrlEncoded(
+                "domain".into(),
+                dns_name.into(),
+            ))
+            .with_body(body)
+            .expect(hits)
+            .create()
+    }
+
+    fn mock_records(server: &mut mockito::Server) -> mockito::Mock {
+        records_mock(server).create()
+    }
+
+    /// Mocks the records of `example.com`, with an enabled and a disabled A record.
+    fn records_mock(server: &mut mockito::Server) -> mockito::Mock {
+        let record = |ip_address: &str, disabled: bool| {
+            json!({
+                "disabled": disabled,
+                "name": "www.example.com",
+                "type": "A",
+                "ttl": 300,
+                "rData": {"ipAddress": ip_address},
+            })
+        };
+        server.mock("POST", "/api/zones/records/get").with_body(
+            json!({
+                "status": "ok",
+                "response": {
+                    "zone": {"name": "example.com", "type": "Primary", "disabled": false},
+                    "records": [record("1.1.1.1", false), record("2.2.2.2", true)],
+                },
+            })
+            .to_string(),
+        )
+    }
+
+    /// A successful response to adding or updating the `2.2.2.2` record.
+    fn applied(field: &str) -> String {
+        json!({
+            "status": "ok",
+            "response": {
+                "zone": {"name": "example.com", "type": "Primary", "disabled": false},
+                field: {
+                    "disabled": false,
+                    "name": "www.example.com",
+                    "type": "A",
+                    "ttl": 300,
+                    "rData": {"ipAddress": "2.2.2.2"},
+                },
+            },
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_health_check_reports_startup_status() {
+        let server = mockito::Server::new_async().await;
+        let state = Arc::new(app_state(&server, DisabledRecordsPolicy::Show));
+
+        for (status, code, expected) in [
+            (
+                StartupStatus::default(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({"state": "connecting", "attempt": 0}),
+            ),
+            (
+                StartupStatus {
+                    state: StartupState::Failed,
+                    attempt: 3,
+                    error: Some("Connection refused".to_string()),
+                },
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({"state": "failed", "attempt": 3, "error": "Connection refused"}),
+            ),
+            (
+                StartupStatus {
+                    state: StartupState::Ready,
+                    attempt: 1,
+                    error: None,
+                },
+                StatusCode::OK,
+                json!({"state": "ready", "attempt": 1}),
+            ),
+        ] {
+            state.startup.send_replace(status);
+            let response = health_check(State(Arc::clone(&state)))
                 .await
-                .delete_record(technitium::DeleteRecordPayload {
-                    domain: ep.dns_name.clone(),
-                    data,
-                    ..Default::default()
-                })
-                .await?;
-        }
-    }
-
-    for ep in additions {
-        for target in ep.targets {
-            let data = match ep.record_type.as_str() {
-                "A" => technitium::RecordAData { ip_address: target }.into(),
-                "AAAA" => technitium::RecordAAAAData { ip_address: target }.into(),
-                "CNAME" => technitium::RecordCNAMEData { cname: target }.into(),
-                "TXT" => technitium::RecordTXTData { text: target }.into(),
-                _ => {
-                    warn!(
-                        "Skipping creation of {} with invalid record type of {}",
-                        ep.dns_name, ep.record_type
-                    );
-                    continue;
-                }
+                .into_response();
+            assert_eq!(response.status(), code);
+            let body = http_body_util::BodyExt::collect(response.into_body())
+                .await
+                .unwrap()
+                .to_bytes();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_endpoints_disabled_records() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+
+        for (policy, expected) in [
+            (DisabledRecordsPolicy::Show, vec!["1.1.1.1", "2.2.2.2"]),
+            (DisabledRecordsPolicy::Hide, vec!["1.1.1.1"]),
+            (DisabledRecordsPolicy::Enable, vec!["1.1.1.1"]),
+        ] {
+            let endpoints = fetch_endpoints(&app_state(&server, policy)).await.unwrap();
+            assert_eq!(endpoints.len(), 1, "{policy:?}");
+            assert_eq!(endpoints[0].targets, expected, "{policy:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes_disabled_records() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let changes = || Changes {
+            create: Some(vec![Endpoint {
+                dns_name: "www.example.com".to_string(),
+                record_type: "A".to_string(),
+                targets: vec!["2.2.2.2".to_string()],
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        // The disabled record is re-enabled rather than added again
+        let enable = server
+            .mock("POST", "/api/zones/records/update")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("ipAddress".into(), "2.2.2.2".into()),
+                mockito::Matcher::UrlEncoded("disable".into(), "false".into()),
+            ]))
+            .with_body(applied("updatedRecord"))
+            .expect(1)
+            .create();
+        let state = app_state(&server, DisabledRecordsPolicy::Enable);
+        apply_changes(&state, changes()).await.unwrap();
+        enable.assert();
+
+        // Otherwise a new record is added, next to the disabled one
+        let add = server
+            .mock("POST", "/api/zones/records/add")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "ipAddress".into(),
+                "2.2.2.2".into(),
+            ))
+            .with_body(applied("addedRecord"))
+            .expect(1)
+            .create();
+        let state = app_state(&server, DisabledRecordsPolicy::Hide);
+        apply_changes(&state, changes()).await.unwrap();
+        add.assert();
+        enable.assert();
+    }
+
+    fn wildcard_endpoints() -> Vec<Endpoint> {
+        ["*.apps.example.com", "foo.*.example.com"]
+            .map(|dns_name| Endpoint {
+                dns_name: dns_name.to_string(),
+                record_type: "A".to_string(),
+                targets: vec!["10.0.0.1".to_string()],
+                ..Default::default()
+            })
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_adjust_endpoints_drops_invalid_wildcard() {
+        let server = mockito::Server::new_async().await;
+        let state = Arc::new(app_state(&server, DisabledRecordsPolicy::Show));
+
+        let response = adjust_endpoints(State(state), Json(wildcard_endpoints()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let endpoints: Vec<Endpoint> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].dns_name, "*.apps.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_apply_record_skips_invalid_wildcard() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let add = server
+            .mock("POST", "/api/zones/records/add")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "domain".into(),
+                "*.apps.example.com".into(),
+            ))
+            .with_body(applied("addedRecord"))
+            .expect(1)
+            .create();
+        let state = Arc::new(app_state(&server, DisabledRecordsPolicy::Show));
+
+        let changes = Changes {
+            create: Some(wildcard_endpoints()),
+            ..Default::default()
+        };
+        let response = apply_record(State(state), Json(changes))
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // Only the valid wildcard is added, as a request for the invalid one doesn't match
+        add.assert();
+    }
+
+    #[tokio::test]
+    async fn test_adjust_endpoints_drops_protected_records() {
+        let server = mockito::Server::new_async().await;
+        let state = Arc::new(ready(Config {
+            protected_records: vec!["protected.example.com".parse().unwrap()],
+            ..config(&server)
+        }));
+
+        let response = adjust_endpoints(
+            State(state),
+            Json(vec![
+                endpoint("protected.example.com", &["10.0.0.1"]),
+                endpoint("www.example.com", &["10.0.0.1"]),
+            ]),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let endpoints: Vec<Endpoint> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(endpoints.len(), 1);
+        assert_eq!(endpoints[0].dns_name, "www.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes_skips_protected_records() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let mocks = [
+            mock_change(&mut server, "add", "protected.example.com", 0),
+            mock_change(&mut server, "update", "protected.example.com", 0),
+            mock_change(&mut server, "delete", "protected.example.com", 0),
+            mock_change(&mut server, "add", "www.example.com", 1),
+        ];
+        let state = ready(Config {
+            protected_records: vec!["protected.example.com".parse().unwrap()],
+            ..config(&server)
+        });
+
+        let changes = Changes {
+            create: Some(vec![
+                endpoint("protected.example.com", &["10.0.0.1"]),
+                endpoint("www.example.com", &["3.3.3.3"]),
+            ]),
+            update_old: Some(vec![endpoint("protected.example.com", &["10.0.0.2"])]),
+            update_new: Some(vec![endpoint("protected.example.com", &["10.0.0.3"])]),
+            delete: Some(vec![endpoint("protected.example.com", &["10.0.0.4"])]),
+        };
+        apply_changes(&state, changes).await.unwrap();
+
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes_drops_changes_by_policy() {
+        // The number of updates and deletes expected to be applied under each policy
+        for (policy, updates, deletes) in [
+            (Policy::Sync, 1, 1),
+            (Policy::UpsertOnly, 1, 0),
+            (Policy::CreateOnly, 0, 0),
+        ] {
+            let mut server = mockito::Server::new_async().await;
+            let _records = mock_records(&mut server);
+            let mocks = [
+                mock_change(&mut server, "add", "new.example.com", 1),
+                mock_change(&mut server, "update", "www.example.com", updates),
+                mock_change(&mut server, "delete", "old.example.com", deletes),
+            ];
+            let state = ready(Config {
+                policy,
+                ..config(&server)
+            });
+
+            let changes = Changes {
+                create: Some(vec![endpoint("new.example.com", &["3.3.3.3"])]),
+                update_old: Some(vec![Endpoint {
+                    record_ttl: Some(300),
+                    ..endpoint("www.example.com", &["1.1.1.1"])
+                }]),
+                update_new: Some(vec![Endpoint {
+                    record_ttl: Some(600),
+                    ..endpoint("www.example.com", &["1.1.1.1"])
+                }]),
+                delete: Some(vec![endpoint("old.example.com", &["10.0.0.4"])]),
+            };
+            apply_changes(&state, changes).await.unwrap();
+
+            for mock in mocks {
+                mock.assert();
+            }
+            let rendered = state.metrics.render();
+            for (change, applied) in [("update", updates), ("delete", deletes)] {
+                let dropped = format!(
+                    "technitium_webhook_policy_dropped_changes_total{{change=\"{change}\"}} {}\n",
+                    1 - applied
+                );
+                assert!(rendered.contains(&dropped), "{policy:?}: {rendered}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_record_refuses_mass_changes() {
+        for (allow_mass_changes, deletes) in [(false, 0), (true, 2)] {
+            let mut server = mockito::Server::new_async().await;
+            let _records = mock_records(&mut server);
+            let delete = mock_change(&mut server, "delete", "old.example.com", deletes);
+            let state = Arc::new(ready(Config {
+                max_changes: Some(ChangeLimit::Count(1)),
+                allow_mass_changes,
+                ..config(&server)
+            }));
+
+            let changes = Changes {
+                delete: Some(vec![endpoint("old.example.com", &["10.0.0.4", "10.0.0.5"])]),
+                ..Default::default()
             };
-            info!("Adding record {} with data {:?}", ep.dns_name, data);
-            app_state
-                .client
-                .read()
+            let response = apply_record(State(state), Json(changes))
                 .await
-                .add_record(technitium::AddRecordPayload {
-                    domain: ep.dns_name.clone(),
-                    ttl: ep.record_ttl,
-                    data,
-                    ..Default::default()
-                })
-                .await?;
+                .into_response();
+
+            let expected = if allow_mass_changes {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::UNPROCESSABLE_ENTITY
+            };
+            assert_eq!(response.status(), expected);
+            delete.assert();
         }
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    #[tokio::test]
+    async fn test_mass_change_guard_counts_applied_records() {
+        let mut server = mockito::Server::new_async().await;
+        // The records are fetched once, for both the guard and the plan
+        let records = records_mock(&mut server).expect(1).create();
+        let delete = mock_change(&mut server, "delete", "www.example.com", 1);
+        let add = mock_change(&mut server, "add", "www.example.com", 1);
+        // Half of the two current records
+        let state = ready(Config {
+            max_changes: Some(ChangeLimit::Percent(50.0)),
+            ..config(&server)
+        });
+
+        // Only the removed target counts as a change, not the kept ones
+        let changes = Changes {
+            update_old: Some(vec![endpoint(
+                "www.example.com",
+                &["1.1.1.1", "5.5.5.5", "6.6.6.6"],
+            )]),
+            update_new: Some(vec![endpoint(
+                "www.example.com",
+                &["1.1.1.1", "5.5.5.5", "7.7.7.7"],
+            )]),
+            ..Default::default()
+        };
+        apply_changes(&state, changes).await.unwrap();
+
+        records.assert();
+        delete.assert();
+        add.assert();
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes_dry_run() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let mocks = ["add", "update", "delete"].map(|action| {
+            server
+                .mock("POST", format!("/api/zones/records/{action}").as_str())
+                .expect(0)
+                .create()
+        });
+        let state = ready(Config {
+            dry_run: true,
+            ..config(&server)
+        });
+
+        let changes = Changes {
+            create: Some(vec![endpoint("new.example.com", &["3.3.3.3"])]),
+            update_old: Some(vec![Endpoint {
+                record_ttl: Some(300),
+                ..endpoint("www.example.com", &["1.1.1.1"])
+            }]),
+            update_new: Some(vec![Endpoint {
+                record_ttl: Some(600),
+                ..endpoint("www.example.com", &["1.1.1.1"])
+            }]),
+            delete: Some(vec![endpoint("old.example.com", &["10.0.0.4"])]),
+        };
+        apply_changes(&state, changes).await.unwrap();
+
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    /// Creates in the zone, outside of the zone and excluded by the domain filter.
+    fn out_of_scope_changes() -> Changes {
+        Changes {
+            create: Some(vec![
+                endpoint("new.example.com", &["3.3.3.3"]),
+                endpoint("www.example.org", &["3.3.3.3"]),
+                endpoint("host.internal.example.com", &["3.3.3.3"]),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn scoped_state(server: &mockito::Server, scope_violations: ScopeViolations) -> AppState {
+        let config = config(server);
+        ready(Config {
+            scope_violations,
+            domain_filter: DomainFilter {
+                exclude: vec!["internal.example.com".to_string()],
+                ..config.domain_filter
+            },
+            ..config
+        })
+    }
+
+    #[tokio::test]
+    async fn test_apply_record_rejects_out_of_scope_changes() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let add = server
+            .mock("POST", "/api/zones/records/add")
+            .expect(0)
+            .create();
+        let state = Arc::new(scoped_state(&server, ScopeViolations::Reject));
+
+        let response = apply_record(State(state), Json(out_of_scope_changes()))
+            .await
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = http_body_util::BodyExt::collect(response.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "error": "Refusing to apply changes to 2 records outside of the managed scope",
+                "violations": [
+                    {
+                        "dnsName": "www.example.org",
+                        "recordType": "A",
+                        "reason": "outside of the managed zones example.com",
+                    },
+                    {
+                        "dnsName": "host.internal.example.com",
+                        "recordType": "A",
+                        "reason": "does not match the domain filter",
+                    },
+                ],
+            })
+        );
+        add.assert();
+    }
+
+    #[tokio::test]
+    async fn test_apply_record_skips_out_of_scope_changes() {
+        let mut server = mockito::Server::new_async().await;
+        let _records = mock_records(&mut server);
+        let mocks = [
+            mock_change(&mut server, "add", "new.example.com", 1),
+            mock_change(&mut server, "add", "www.example.org", 0),
+            mock_change(&mut server, "add", "host.internal.example.com", 0),
+        ];
+        let state = Arc::new(scoped_state(&server, ScopeViolations::Skip));
+
+        let response = apply_record(State(state), Json(out_of_scope_changes()))
+            .await
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        for mock in mocks {
+            mock.assert();
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use app::{AppError, AppState};
+use app::{AppError, AppState, StartupState, StartupStatus};
 use axum::{
     Router,
     body::{Body, Bytes},
@@ -8,40 +8,56 @@ use axum::{
     response::{IntoResponse, Response},
     routing::{get, post},
 };
-use config::Config;
+use backoff::Backoff;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{Config, Credentials};
 use http_body_util::BodyExt;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
-use tokio::sync::RwLock;
-use tokio::time::sleep;
+use tokio::time::{Instant, sleep};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod app;
+mod backoff;
+mod cli;
 mod config;
+mod config_file;
+mod filter;
 mod handlers;
+mod metrics;
 mod models;
+mod normalize;
+mod pattern;
+mod plan;
 mod technitium;
-
-const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+mod ttl;
+mod txt;
 
 async fn check_zone_existence(
-    app_state: &Arc<AppState>,
+    app_state: &AppState,
+    zone: &str,
 ) -> Result<bool, technitium::TechnitiumError> {
-    let client = app_state.client.read().await;
     let mut page_number = 1;
 
     loop {
-        let zones = client
-            .list_zones(technitium::ListZonesPayload {
-                zone: app_state.config.zone.clone(),
-                page_number: Some(page_number),
-                zones_per_page: Some(100),
+        let zones = app_state
+            .with_client(|client| async move {
+                client
+                    .list_zones(technitium::ListZonesPayload {
+                        zone: zone.to_string(),
+                        page_number: Some(page_number),
+                        zones_per_page: Some(100),
+                    })
+                    .await
             })
             .await?;
-        if zones.zones.iter().any(|z| z.name == app_state.config.zone) {
+        if zones.zones.iter().any(|z| z.name == zone) {
             return Ok(true);
         }
         if page_number >= zones.total_pages {
@@ -52,75 +68,120 @@ async fn check_zone_existence(
     Ok(false)
 }
 
-async fn create_default_zone(app_state: &Arc<AppState>) -> Result<(), technitium::TechnitiumError> {
-    let client = app_state.client.read().await;
+async fn create_default_zone(
+    app_state: &AppState,
+    zone: &str,
+) -> Result<(), technitium::TechnitiumError> {
     let payload = technitium::CreateZonePayload {
-        zone: app_state.config.zone.clone(),
+        zone: zone.to_string(),
         zone_type: technitium::ZoneType::Forwarder,
         protocol: Some(technitium::Protocol::Udp),
         forwarder: Some("this-server".to_string()),
         dnssec_validation: Some(true),
-        ..Default::default()
     };
 
-    client.create_zone(payload).await?;
+    app_state
+        .with_client(|client| {
+            let payload = payload.clone();
+            async move { client.create_zone(payload).await }
+        })
+        .await?;
     info!(
         "Zone {} created successfully in Technitium DNS server.",
-        &app_state.config.zone
+        zone
     );
     Ok(())
 }
 
-async fn setup_technitium_connection(app_state: Arc<AppState>) {
-    // Construct the login payload using the credentials from the configuration
-    let login_payload = technitium::LoginPayload {
-        username: app_state.config.technitium_username.clone(),
-        password: app_state.config.technitium_password.clone(),
-    };
+/// Connects to Technitium and prepares the zones, retrying with the backoff until it
+/// succeeds or the startup deadline passes.
+async fn setup_technitium_connection(app_state: Arc<AppState>, mut backoff: Backoff) {
+    // Watch the credentials from the start, as they may be fixed while failing to log in
+    if app_state.config.watch_credentials {
+        tokio::spawn(watch_credential_files(Arc::clone(&app_state)));
+    }
 
-    // Attempt to log in to Technitium using the login payload
-    let token = match app_state.client.read().await.login(login_payload).await {
-        Ok(resp) => resp.token,
-        Err(e) => {
-            error!("Failed to log in to Technitium DNS server: {}", e);
-            std::process::exit(1);
+    // A timeout too large to be represented means there is no deadline
+    let deadline = app_state
+        .config
+        .startup_timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut attempt = 1;
+    loop {
+        let Err(e) = try_setup_technitium_connection(&app_state, attempt).await else {
+            break;
+        };
+
+        let delay = backoff.next_delay();
+        if deadline.is_some_and(|deadline| Instant::now() + delay > deadline) {
+            error!("Giving up connecting to Technitium DNS server after {attempt} attempts: {e}");
+            app_state.startup.send_replace(StartupStatus {
+                state: StartupState::Failed,
+                attempt,
+                error: Some(e),
+            });
+            return;
         }
-    };
 
-    info!("Successfully logged into Technitium DNS server with received token.");
-    *app_state.client.write().await = technitium::TechnitiumClient::new(
-        app_state.config.technitium_url.clone(),
-        token,
-        HTTP_TIMEOUT,
-    );
+        warn!("{e}, retrying in {delay:?}");
+        app_state
+            .startup
+            .send_modify(|status| status.error = Some(e));
+        sleep(delay).await;
+        attempt += 1;
+    }
+
     tokio::spawn(auto_renew_technitium_token(Arc::clone(&app_state)));
 
-    debug!("Verifying and preparing the DNS Zone...");
+    info!("Connected to Technitium DNS server, ready to serve requests.");
+    app_state.startup.send_replace(StartupStatus {
+        state: StartupState::Ready,
+        attempt,
+        error: None,
+    });
+}
 
-    let zone_exists = match check_zone_existence(&app_state).await {
-        Ok(ret) => ret,
-        Err(e) => {
-            error!("Failed to list zones: {}", e);
-            std::process::exit(1);
-        }
+async fn try_setup_technitium_connection(app_state: &AppState, attempt: u32) -> Result<(), String> {
+    let set_state = |state| {
+        app_state.startup.send_modify(|status| {
+            status.state = state;
+            status.attempt = attempt;
+        })
     };
 
-    if zone_exists {
-        info!(
-            "Zone {} exists in Technitium DNS server.",
-            &app_state.config.zone
-        );
-    } else {
-        if let Err(e) = create_default_zone(&app_state).await {
-            error!(
-                "Failed to create the zone {} in Technitium DNS server: {}",
-                &app_state.config.zone, e
+    set_state(StartupState::Connecting);
+    app_state
+        .authenticate()
+        .await
+        .map_err(|e| format!("Failed to log in to Technitium DNS server: {e}"))?;
+
+    debug!("Verifying and preparing the DNS Zones...");
+    set_state(StartupState::Preparing);
+
+    for zone in &app_state.config.zones {
+        let zone_exists = check_zone_existence(app_state, &zone.name)
+            .await
+            .map_err(|e| format!("Failed to list zones: {e}"))?;
+
+        if zone_exists {
+            info!("Zone {} exists in Technitium DNS server.", zone.name);
+        } else if app_state.config.dry_run {
+            info!(
+                "Dry run, not creating the missing zone {} in Technitium DNS server.",
+                zone.name
             );
-            std::process::exit(1);
+        } else {
+            create_default_zone(app_state, &zone.name)
+                .await
+                .map_err(|e| {
+                    format!(
+                        "Failed to create the zone {} in Technitium DNS server: {e}",
+                        zone.name
+                    )
+                })?;
         }
     }
-
-    *app_state.is_ready.write().await = true;
+    Ok(())
 }
 
 async fn auto_renew_technitium_token(app_state: Arc<AppState>) {
@@ -129,64 +190,119 @@ async fn auto_renew_technitium_token(app_state: Arc<AppState>) {
 
     let mut sleep_for = DURATION_SUCCESS;
     loop {
-        sleep(sleep_for).await;
+        tokio::select! {
+            _ = sleep(sleep_for) => {
+                // API tokens don't expire, so they're only renewed when rotated
+                if matches!(*app_state.credentials.read().await, Credentials::Token(_)) {
+                    continue;
+                }
+            }
+            _ = app_state.credentials_changed.notified() => {}
+        }
 
-        // Construct the login payload using the credentials from the configuration
-        let login_payload = technitium::LoginPayload {
-            username: app_state.config.technitium_username.clone(),
-            password: app_state.config.technitium_password.clone(),
-        };
+        if let Err(e) = app_state.authenticate().await {
+            error!("Failed to renew Technitium DNS server access token: {}", e);
+            sleep_for = DURATION_FAILURE;
+            continue;
+        }
 
-        // Attempt to log in to Technitium using the login payload
-        let token = match app_state.client.read().await.login(login_payload).await {
-            Ok(resp) => resp.token,
+        info!("Successfully renewed Technitium DNS server access token.");
+        sleep_for = DURATION_SUCCESS;
+    }
+}
+
+async fn watch_credential_files(app_state: Arc<AppState>) {
+    const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+    loop {
+        sleep(POLL_INTERVAL).await;
+
+        // Credential files may be briefly missing while being rotated, so keep using the old ones
+        let credentials = match Credentials::reload(app_state.config.config_file.as_deref()) {
+            Ok(credentials) => credentials,
             Err(e) => {
-                error!("Failed to renew Technitium DNS server access token: {}", e);
-                sleep_for = DURATION_FAILURE;
+                warn!("Failed to reload Technitium DNS server credentials: {}", e);
                 continue;
             }
         };
+        if *app_state.credentials.read().await == credentials {
+            continue;
+        }
 
-        info!("Successfully renewed Technitium DNS server access token.");
-        *app_state.client.write().await = technitium::TechnitiumClient::new(
-            app_state.config.technitium_url.clone(),
-            token,
-            HTTP_TIMEOUT,
-        );
-        sleep_for = DURATION_SUCCESS;
+        info!("Technitium DNS server credentials changed, renewing access token.");
+        *app_state.credentials.write().await = credentials;
+        app_state.credentials_changed.notify_one();
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // initialize tracing
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // initialize tracing, keeping stdout free for the output of the other commands
+    let writer = match command {
+        Command::Serve => BoxMakeWriter::new(std::io::stdout),
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
                 format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
-    let config = Config::from_env();
-    let client = technitium::TechnitiumClient::new(
-        config.technitium_url.clone(),
-        Default::default(), // no token initially
-        HTTP_TIMEOUT,
-    );
-    let app_state = Arc::new(AppState {
-        config,
-        is_ready: RwLock::new(false),
-        client: RwLock::new(client),
-    });
+    let (config, credentials) = match Config::load(cli.config) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            for e in errors {
+                error!("{}", e);
+            }
+            error!("Invalid configuration, exiting.");
+            return ExitCode::from(cli::EXIT_INVALID_CONFIG);
+        }
+    };
+    let app_state = match AppState::new(config, credentials) {
+        Ok(app_state) => Arc::new(app_state),
+        Err(e) => {
+            error!("Invalid Technitium DNS connection settings: {}", e);
+            return ExitCode::from(cli::EXIT_INVALID_CONFIG);
+        }
+    };
+
+    match command {
+        Command::Serve => match serve(app_state).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(_) => ExitCode::FAILURE,
+        },
+        Command::Check => cli::check(&app_state).await,
+        Command::List => cli::list(&app_state).await,
+        Command::Apply { file } => cli::apply(&app_state, &file).await,
+    }
+}
+
+async fn serve(app_state: Arc<AppState>) -> Result<(), Box<dyn std::error::Error>> {
+    const STARTUP_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+    const STARTUP_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
     // Check and create zone if necessary
-    tokio::spawn(setup_technitium_connection(Arc::clone(&app_state)));
+    tokio::spawn(setup_technitium_connection(
+        Arc::clone(&app_state),
+        Backoff::new(STARTUP_BACKOFF_INITIAL, STARTUP_BACKOFF_MAX),
+    ));
+    let mut startup = app_state.startup.subscribe();
+    let startup_failed = async move {
+        let _ = startup
+            .wait_for(|status| status.state == StartupState::Failed)
+            .await;
+    };
 
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(handlers::health_check))
+        .route("/metrics", get(handlers::metrics))
         .route("/", get(handlers::negotiate_domain_filter))
         .route("/records", get(handlers::get_records))
         .route("/adjustendpoints", post(handlers::adjust_endpoints))
@@ -205,12 +321,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("listening on {}", listener.local_addr()?);
 
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(async {
+            tokio::select! {
+                _ = shutdown_signal() => {},
+                _ = startup_failed => {},
+            }
+        })
         .await
         .map_err(|e| {
             error!("Server error: {}", e);
-            e.into()
-        })
+            e
+        })?;
+
+    if app_state.startup.borrow().state == StartupState::Failed {
+        return Err("failed to connect to Technitium DNS server".into());
+    }
+    Ok(())
 }
 
 async fn shutdown_signal() {
@@ -275,3 +401,106 @@ where
 
     Ok(bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, Credentials, Zone};
+    use serde_json::json;
+
+    fn app_state(server: &mockito::Server, startup_timeout: Option<Duration>) -> Arc<AppState> {
+        let config = Config {
+            technitium_url: server.url(),
+            zones: vec![Zone {
+                name: "example.com".to_string(),
+                ..Default::default()
+            }],
+            retry_policy: technitium::RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            },
+            startup_timeout,
+            ..Default::default()
+        };
+        Arc::new(AppState::new(config, Credentials::Token("token".to_string())).unwrap())
+    }
+
+    fn fast_backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(10), Duration::from_millis(10))
+    }
+
+    #[tokio::test]
+    async fn test_setup_retries_until_connected() {
+        let mut server = mockito::Server::new_async().await;
+        // Mocks are matched in order until their expected number of requests is reached
+        let unavailable = server
+            .mock("POST", "/api/user/session/get")
+            .with_status(503)
+            .expect(2)
+            .create();
+        let session = server
+            .mock("POST", "/api/user/session/get")
+            .with_body(
+                json!({"status": "ok", "displayName": "External DNS", "username": "external-dns"})
+                    .to_string(),
+            )
+            .expect(1)
+            .create();
+        let zones = server
+            .mock("POST", "/api/zones/list")
+            .with_body(
+                json!({
+                    "status": "ok",
+                    "response": {
+                        "pageNumber": 1,
+                        "totalPages": 1,
+                        "totalZones": 1,
+                        "zones": [{"name": "example.com", "type": "Primary", "disabled": false}],
+                    },
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        // A huge timeout means no deadline, rather than overflowing
+        let app_state = app_state(&server, Some(Duration::MAX));
+        setup_technitium_connection(Arc::clone(&app_state), fast_backoff()).await;
+
+        unavailable.assert();
+        session.assert();
+        zones.assert();
+        assert_eq!(
+            *app_state.startup.borrow(),
+            StartupStatus {
+                state: StartupState::Ready,
+                attempt: 3,
+                error: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_gives_up_at_startup_timeout() {
+        let mut server = mockito::Server::new_async().await;
+        let _unavailable = server
+            .mock("POST", "/api/user/session/get")
+            .with_status(503)
+            .create();
+
+        let app_state = app_state(&server, Some(Duration::from_millis(100)));
+        let started = Instant::now();
+        setup_technitium_connection(Arc::clone(&app_state), fast_backoff()).await;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let status = app_state.startup.borrow().clone();
+        assert_eq!(status.state, StartupState::Failed);
+        assert!(status.attempt > 1, "{status:?}");
+        assert!(
+            status
+                .error
+                .unwrap()
+                .starts_with("Failed to log in to Technitium DNS server")
+        );
+    }
+}
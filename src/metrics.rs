@@ -0,0 +1,72 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters exposed in the Prometheus text format on `/metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub dropped_deletes: AtomicU64,
+    pub dropped_updates: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let dropped = [
+            ("delete", &self.dropped_deletes),
+            ("update", &self.dropped_updates),
+        ];
+        write_counter(
+            &mut out,
+            "technitium_webhook_policy_dropped_changes_total",
+            "Changes dropped because of the configured policy.",
+            "change",
+            &dropped,
+        );
+        out
+    }
+}
+
+fn write_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &[(&str, &AtomicU64)],
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (value, counter) in values {
+        let _ = writeln!(
+            out,
+            "{name}{{{label}=\"{value}\"}} {}",
+            counter.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.dropped_deletes, 2);
+        let rendered = metrics.render();
+        assert!(
+            rendered.contains("# TYPE technitium_webhook_policy_dropped_changes_total counter\n")
+        );
+        assert!(
+            rendered
+                .contains("technitium_webhook_policy_dropped_changes_total{change=\"delete\"} 2\n")
+        );
+        assert!(
+            rendered
+                .contains("technitium_webhook_policy_dropped_changes_total{change=\"update\"} 0\n")
+        );
+    }
+}
@@ -5,9 +5,23 @@ fn is_default<T: Default + PartialEq>(t: &T) -> bool {
     t == &T::default()
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Filters {
     pub filters: Vec<String>,
+    #[serde(skip_serializing_if = "is_default")]
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "is_default")]
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(skip_serializing_if = "is_default")]
+    #[serde(default)]
+    #[serde(rename = "regexInclude")]
+    pub regex_include: String,
+    #[serde(skip_serializing_if = "is_default")]
+    #[serde(default)]
+    #[serde(rename = "regexExclude")]
+    pub regex_exclude: String,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone, Default)]
@@ -40,7 +54,7 @@ pub struct ProviderSpecificProperty {
     pub value: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct Changes {
     #[serde(alias = "Create")]
     pub create: Option<Vec<Endpoint>>,
@@ -0,0 +1,285 @@
+use crate::models::Endpoint;
+use crate::txt;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Normalises an endpoint into the canonical form used for both the desired
+/// and the current state, so that external-dns doesn't see spurious diffs.
+pub fn normalize_endpoint(mut ep: Endpoint) -> Endpoint {
+    ep.dns_name = normalize_name(&ep.dns_name);
+    ep.targets = ep
+        .targets
+        .iter()
+        .map(|target| normalize_target(&ep.record_type, target))
+        .collect();
+    ep.targets.sort();
+    ep.targets.dedup();
+    ep
+}
+
+/// Groups endpoints with the same name and record type into a single
+/// endpoint with all of their targets, ordered by name and record type.
+///
+/// Endpoints are normalized first, so names differing only in case, a trailing
+/// dot or their IDN form end up in the same group. When the TTLs of the grouped
+/// endpoints differ, the lowest TTL is used.
+pub fn group_endpoints(endpoints: Vec<Endpoint>) -> Vec<Endpoint> {
+    let mut groups = BTreeMap::<(String, String), Endpoint>::new();
+    for ep in endpoints.into_iter().map(normalize_endpoint) {
+        let key = (ep.dns_name.clone(), ep.record_type.clone());
+        match groups.get_mut(&key) {
+            Some(group) => {
+                group.targets.extend(ep.targets);
+                group.record_ttl = match (group.record_ttl, ep.record_ttl) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+            None => {
+                groups.insert(key, ep);
+            }
+        }
+    }
+    groups.into_values().map(normalize_endpoint).collect()
+}
+
+/// Lowercases the name, removes the trailing dot and converts punycode
+/// labels to Unicode, which is the form external-dns uses for IDNs.
+pub fn normalize_name(name: &str) -> String {
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    match idna::domain_to_unicode(&name) {
+        (unicode, Ok(())) => unicode,
+        (_, Err(_)) => name,
+    }
+}
+
+/// Converts the name to the ASCII (punycode) form stored by Technitium,
+/// validating its labels.
+pub fn to_ascii(name: &str) -> Result<String, String> {
+    let ascii = idna::domain_to_ascii(name.trim().trim_end_matches('.'))
+        .map_err(|e| format!("invalid domain name {name}: {e}"))?;
+    if ascii.len() > 253 {
+        return Err(format!(
+            "invalid domain name {name}: longer than 253 characters"
+        ));
+    }
+    for (i, label) in ascii.split('.').enumerate() {
+        if label.contains('*') && (label != "*" || i > 0) {
+            return Err(format!(
+                "invalid domain name {name}: a wildcard must be the whole leftmost label, like *.example.com"
+            ));
+        }
+        if label.is_empty() {
+            return Err(format!("invalid domain name {name}: empty label"));
+        }
+        if label.len() > 63 {
+            return Err(format!(
+                "invalid domain name {name}: label {label} is longer than 63 characters"
+            ));
+        }
+        if !label
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '*'))
+        {
+            return Err(format!(
+                "invalid domain name {name}: label {label} contains invalid characters"
+            ));
+        }
+    }
+    Ok(ascii)
+}
+
+/// Validates the name and, for CNAME records, the targets of the endpoint.
+pub fn validate_endpoint(ep: &Endpoint) -> Result<(), String> {
+    to_ascii(&ep.dns_name)?;
+    if ep.record_type == "CNAME" {
+        for target in &ep.targets {
+            to_ascii(target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Normalises a target of the given record type.
+pub fn normalize_target(record_type: &str, target: &str) -> String {
+    match record_type {
+        "A" | "AAAA" => target
+            .trim()
+            .parse::<IpAddr>()
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|_| target.trim().to_string()),
+        "CNAME" => normalize_name(target),
+        "TXT" => txt::normalize(target),
+        _ => target.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(dns_name: &str, record_type: &str, targets: &[&str]) -> Endpoint {
+        Endpoint {
+            dns_name: dns_name.to_string(),
+            record_type: record_type.to_string(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_group_endpoints() {
+        let mut a1 = endpoint("www.example.com", "A", &["10.0.0.2"]);
+        a1.record_ttl = Some(300);
+        let mut a2 = endpoint("www.example.com", "A", &["10.0.0.1"]);
+        a2.record_ttl = Some(60);
+        let txt = endpoint("www.example.com", "TXT", &["hello"]);
+        let apex = endpoint("example.com", "A", &["10.0.0.3"]);
+
+        let grouped = group_endpoints(vec![a1, txt.clone(), apex.clone(), a2]);
+
+        let mut a = endpoint("www.example.com", "A", &["10.0.0.1", "10.0.0.2"]);
+        a.record_ttl = Some(60);
+        assert_eq!(grouped, vec![apex, a, txt]);
+    }
+
+    #[test]
+    fn test_group_endpoints_normalizes_before_grouping() {
+        // Technitium may return names differing only in case or a trailing dot, as well
+        // as punycode, which must all end up in the same record set
+        let grouped = group_endpoints(vec![
+            endpoint("www.example.com", "A", &["10.0.0.1"]),
+            endpoint("WWW.Example.com.", "A", &["10.0.0.2"]),
+            endpoint("xn--bcher-kva.example.com", "TXT", &["a"]),
+            endpoint("bücher.example.com", "TXT", &["b"]),
+        ]);
+
+        assert_eq!(
+            grouped,
+            vec![
+                endpoint("bücher.example.com", "TXT", &["a", "b"]),
+                endpoint("www.example.com", "A", &["10.0.0.1", "10.0.0.2"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_wildcard_endpoints() {
+        let grouped = group_endpoints(vec![
+            endpoint("*.apps.example.com", "A", &["10.0.0.2"]),
+            endpoint("apps.example.com", "A", &["10.0.0.3"]),
+            endpoint("*.Apps.example.com.", "A", &["10.0.0.1"]),
+        ]);
+
+        assert_eq!(
+            grouped,
+            vec![
+                endpoint("*.apps.example.com", "A", &["10.0.0.1", "10.0.0.2"]),
+                endpoint("apps.example.com", "A", &["10.0.0.3"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("WWW.Example.COM."), "www.example.com");
+        assert_eq!(normalize_name("bücher.example.com"), "bücher.example.com");
+        assert_eq!(
+            normalize_name("xn--bcher-kva.example.com"),
+            "bücher.example.com"
+        );
+        assert_eq!(normalize_name("BÜCHER.example.com"), "bücher.example.com");
+        assert_eq!(normalize_name("*.apps.example.com"), "*.apps.example.com");
+        assert_eq!(
+            normalize_name("_acme-challenge.example.com"),
+            "_acme-challenge.example.com"
+        );
+    }
+
+    #[test]
+    fn test_normalize_ip_targets() {
+        let ep = normalize_endpoint(endpoint(
+            "Example.com.",
+            "AAAA",
+            &["2001:DB8:0:0:0:0:0:1", "2001:db8::1", " fe80::0001 "],
+        ));
+        assert_eq!(ep.dns_name, "example.com");
+        assert_eq!(ep.targets, vec!["2001:db8::1", "fe80::1"]);
+
+        let ep = normalize_endpoint(endpoint("example.com", "A", &["10.0.0.2", "10.0.0.1"]));
+        assert_eq!(ep.targets, vec!["10.0.0.1", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn test_normalize_cname_target() {
+        let ep = normalize_endpoint(endpoint("www.example.com", "CNAME", &["Example.COM."]));
+        assert_eq!(ep.targets, vec!["example.com"]);
+    }
+
+    #[test]
+    fn test_normalize_txt_target() {
+        let ep = normalize_endpoint(endpoint("example.com", "TXT", &["Hello World."]));
+        assert_eq!(ep.targets, vec!["Hello World."]);
+
+        let ep = normalize_endpoint(endpoint("example.com", "TXT", &["\"Hello World.\""]));
+        assert_eq!(ep.targets, vec!["Hello World."]);
+    }
+
+    #[test]
+    fn test_to_ascii() {
+        assert_eq!(
+            to_ascii("bücher.example.com."),
+            Ok("xn--bcher-kva.example.com".to_string())
+        );
+        assert_eq!(
+            to_ascii("WWW.example.com"),
+            Ok("www.example.com".to_string())
+        );
+        assert_eq!(
+            to_ascii("_dmarc.example.com"),
+            Ok("_dmarc.example.com".to_string())
+        );
+        assert!(to_ascii("xn--zz.example.com").is_err());
+        assert!(to_ascii("a..example.com").is_err());
+        assert!(to_ascii("bad name.example.com").is_err());
+        assert!(to_ascii(&format!("{}.example.com", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn test_wildcard_names() {
+        assert_eq!(
+            to_ascii("*.apps.example.com"),
+            Ok("*.apps.example.com".to_string())
+        );
+        assert_eq!(
+            to_ascii("*.bücher.example.com"),
+            Ok("*.xn--bcher-kva.example.com".to_string())
+        );
+        assert_eq!(normalize_name("*.Apps.Example.com."), "*.apps.example.com");
+        assert!(
+            to_ascii("foo.*.example.com")
+                .unwrap_err()
+                .contains("wildcard must be the whole leftmost label")
+        );
+        assert!(to_ascii("*foo.example.com").is_err());
+        assert!(to_ascii("foo*.example.com").is_err());
+        assert!(to_ascii("*.*.example.com").is_err());
+    }
+
+    #[test]
+    fn test_validate_endpoint() {
+        assert!(validate_endpoint(&endpoint("bücher.example.com", "A", &["10.0.0.1"])).is_ok());
+        assert!(
+            validate_endpoint(&endpoint(
+                "www.example.com",
+                "CNAME",
+                &["bücher.example.com"]
+            ))
+            .is_ok()
+        );
+        assert!(validate_endpoint(&endpoint("a..example.com", "A", &["10.0.0.1"])).is_err());
+        assert!(
+            validate_endpoint(&endpoint("www.example.com", "CNAME", &["bad name.com"])).is_err()
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use crate::normalize::normalize_name;
+use std::fmt;
+use std::str::FromStr;
+
+/// A case-insensitive DNS name pattern, where `*` matches any sequence of characters.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NamePattern(String);
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = normalize_name(name);
+        glob_match(self.0.as_bytes(), name.as_bytes())
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = normalize_name(s);
+        if pattern.is_empty() {
+            return Err("empty name pattern".to_string());
+        }
+        Ok(Self(pattern))
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Selects records by name pattern and, optionally, by record type.
+///
+/// Parsed from `pattern[:TYPE,TYPE,...]`, e.g. `*.vpn.example.com:A,AAAA`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordSelector {
+    pub pattern: NamePattern,
+    /// Record types to match, matches all record types if empty.
+    pub record_types: Vec<String>,
+}
+
+impl RecordSelector {
+    pub fn matches(&self, name: &str, record_type: &str) -> bool {
+        (self.record_types.is_empty()
+            || self
+                .record_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(record_type)))
+            && self.pattern.matches(name)
+    }
+}
+
+impl FromStr for RecordSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, record_types) = match s.split_once(':') {
+            Some((pattern, types)) => (
+                pattern,
+                types
+                    .split(',')
+                    .map(|t| t.trim().to_ascii_uppercase())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
+            ),
+            None => (s, Vec::new()),
+        };
+        Ok(Self {
+            pattern: pattern.parse()?,
+            record_types,
+        })
+    }
+}
+
+impl fmt::Display for RecordSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)?;
+        if !self.record_types.is_empty() {
+            write!(f, ":{}", self.record_types.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((bp, bt)) = backtrack {
+            p = bp + 1;
+            t = bt + 1;
+            backtrack = Some((bp, bt + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_pattern_matches() {
+        let pattern: NamePattern = "mail.example.com".parse().unwrap();
+        assert!(pattern.matches("mail.example.com"));
+        assert!(pattern.matches("MAIL.example.com."));
+        assert!(!pattern.matches("smtp.mail.example.com"));
+
+        let pattern: NamePattern = "*.vpn.example.com".parse().unwrap();
+        assert!(pattern.matches("a.vpn.example.com"));
+        assert!(pattern.matches("a.b.vpn.example.com"));
+        assert!(pattern.matches("*.vpn.example.com"));
+        assert!(!pattern.matches("vpn.example.com"));
+
+        let pattern: NamePattern = "*".parse().unwrap();
+        assert!(pattern.matches("anything.example.com"));
+    }
+
+    #[test]
+    fn test_record_selector_parsing() {
+        let selector: RecordSelector = "example.com:mx, A".parse().unwrap();
+        assert_eq!(selector.pattern, "example.com".parse().unwrap());
+        assert_eq!(selector.record_types, vec!["MX", "A"]);
+
+        let selector: RecordSelector = "example.com".parse().unwrap();
+        assert!(selector.record_types.is_empty());
+
+        assert!(":A".parse::<RecordSelector>().is_err());
+    }
+
+    #[test]
+    fn test_record_selector_matches() {
+        let selector: RecordSelector = "example.com:A".parse().unwrap();
+        assert!(selector.matches("example.com", "A"));
+        assert!(selector.matches("example.com", "a"));
+        assert!(!selector.matches("example.com", "TXT"));
+        assert!(!selector.matches("www.example.com", "A"));
+
+        let selector: RecordSelector = "vpn.example.com".parse().unwrap();
+        assert!(selector.matches("vpn.example.com", "TXT"));
+    }
+}
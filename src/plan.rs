@@ -0,0 +1,875 @@
+use crate::config::DisabledRecordsPolicy;
+use crate::models::Endpoint;
+use crate::normalize::{normalize_name, normalize_target, to_ascii};
+use crate::technitium::{
+    self, AddRecordPayload, DeleteRecordPayload, RecordAAAAData, RecordAData, RecordCNAMEData,
+    RecordData, RecordInfo, RecordTXTData, TechnitiumClient, TechnitiumError, UpdateRecordPayload,
+};
+use crate::txt;
+use std::fmt;
+use tracing::{info, warn};
+
+/// A single change to be made on the Technitium DNS server.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Delete(DeleteRecordPayload),
+    Add(AddRecordPayload),
+    Update(UpdateRecordPayload),
+}
+
+impl Operation {
+    pub async fn execute(self, client: &TechnitiumClient) -> Result<(), TechnitiumError> {
+        match self {
+            Operation::Delete(payload) => {
+                info!(
+                    "Deleting record {} with data {:?}",
+                    payload.domain, payload.data
+                );
+                client.delete_record(payload).await?;
+            }
+            Operation::Add(payload) => {
+                info!(
+                    "Adding record {} with data {:?}",
+                    payload.domain, payload.data
+                );
+                client.add_record(payload).await?;
+            }
+            Operation::Update(payload) => {
+                info!(
+                    "Updating record {} with data {:?}",
+                    payload.domain, payload.data
+                );
+                client.update_record(payload).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An endpoint being updated by external-dns from `old` to `new`.
+#[derive(Debug, Clone)]
+pub struct Update {
+    pub old: Endpoint,
+    pub new: Endpoint,
+}
+
+impl Update {
+    /// Splits the targets into the ones kept, removed and added by the update.
+    ///
+    /// Targets are compared in their normalized form, like the current records are
+    /// matched, so e.g. a change in case only doesn't replace the record.
+    fn split_targets(&self) -> (Vec<String>, Vec<String>, Vec<String>) {
+        let normalize = |t: &String| normalize_target(&self.new.record_type, t);
+        let old = self.old.targets.iter().map(normalize).collect::<Vec<_>>();
+        let new = self.new.targets.iter().map(normalize).collect::<Vec<_>>();
+        let (kept, removed) = self
+            .old
+            .targets
+            .iter()
+            .cloned()
+            .partition(|t| new.contains(&normalize(t)));
+        let added = self
+            .new
+            .targets
+            .iter()
+            .filter(|t| !old.contains(&normalize(t)))
+            .cloned()
+            .collect();
+        (kept, removed, added)
+    }
+
+    fn changes_ttl(&self) -> bool {
+        self.new.record_ttl.is_some() && self.new.record_ttl != self.old.record_ttl
+    }
+
+    /// Returns the number of existing records the update deletes or updates in place.
+    pub fn changed_records(&self) -> usize {
+        let (kept, removed, _) = self.split_targets();
+        removed.len() + if self.changes_ttl() { kept.len() } else { 0 }
+    }
+}
+
+/// Pairs up the old and new endpoints of updates by name, record type and set identifier.
+///
+/// Endpoints without a counterpart are paired with an endpoint without targets,
+/// so their targets are all deleted or all added.
+pub fn pair_updates(update_old: Vec<Endpoint>, mut update_new: Vec<Endpoint>) -> Vec<Update> {
+    let mut updates = Vec::new();
+    for old in update_old {
+        let position = update_new.iter().position(|new| {
+            new.dns_name == old.dns_name
+                && new.record_type == old.record_type
+                && new.set_identifier == old.set_identifier
+        });
+        let new = match position {
+            Some(position) => update_new.remove(position),
+            None => Endpoint {
+                targets: Vec::new(),
+                ..old.clone()
+            },
+        };
+        updates.push(Update { old, new });
+    }
+    for new in update_new {
+        let old = Endpoint {
+            targets: Vec::new(),
+            ..new.clone()
+        };
+        updates.push(Update { old, new });
+    }
+    updates
+}
+
+/// A record to be added that can't coexist with other records of the same name.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Conflict {
+    pub dns_name: String,
+    pub record_type: String,
+    pub target: String,
+    /// The conflicting records, formatted as `TYPE target`.
+    pub conflicting: Vec<String>,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} conflicts with {}",
+            self.dns_name,
+            self.record_type,
+            self.target,
+            self.conflicting.join(", ")
+        )
+    }
+}
+
+/// Computes the operations needed to apply the given deletions, updates and additions.
+///
+/// Updates only touch the targets that actually changed, and the targets kept
+/// by an update are only updated in place when their TTL changes. With the
+/// [`DisabledRecordsPolicy::Enable`] policy, additions matching a disabled
+/// record re-enable that record instead of adding a new one.
+///
+/// Deletions are always ordered before additions, so records conflicting with
+/// a CNAME are removed first when the batch deletes them. Any remaining
+/// conflict with the current `records` is returned as an error instead.
+pub fn plan(
+    mut deletions: Vec<Endpoint>,
+    updates: Vec<Update>,
+    mut additions: Vec<Endpoint>,
+    records: &[RecordInfo],
+    disabled_records: DisabledRecordsPolicy,
+) -> Result<Vec<Operation>, Vec<Conflict>> {
+    let mut operations = Vec::new();
+    // Deletes and updates are sent with the data the record is stored with, which may differ
+    // from the normalized target, e.g. TXT values stored with their quotes
+    let current = records.iter().collect::<Vec<_>>();
+
+    for update in updates {
+        let (kept, removed, added) = update.split_targets();
+        let changes_ttl = update.changes_ttl();
+        let Update { old, new } = update;
+
+        if changes_ttl {
+            for target in &kept {
+                let (domain, data) =
+                    match find_record(&current, &new.dns_name, &new.record_type, target) {
+                        Some(ri) => (ri.name.clone(), update_data(&ri.data)),
+                        None => match update_record_data(&new.record_type, target.clone()) {
+                            Some(data) => (ascii(&new.dns_name), data),
+                            None => continue,
+                        },
+                    };
+                operations.push(Operation::Update(UpdateRecordPayload {
+                    domain,
+                    data,
+                    ttl: new.record_ttl,
+                    ..Default::default()
+                }));
+            }
+        }
+        deletions.push(Endpoint {
+            targets: removed,
+            ..old
+        });
+        additions.push(Endpoint {
+            targets: added,
+            ..new
+        });
+    }
+
+    let conflicts = find_conflicts(&deletions, &additions, records);
+    if !conflicts.is_empty() {
+        return Err(conflicts);
+    }
+
+    let disabled_records = match disabled_records {
+        DisabledRecordsPolicy::Enable => records.iter().filter(|ri| ri.disabled).collect(),
+        _ => Vec::new(),
+    };
+
+    for ep in deletions {
+        for target in ep.targets {
+            if let Some(ri) = find_record(&current, &ep.dns_name, &ep.record_type, &target) {
+                operations.push(Operation::Delete(DeleteRecordPayload {
+                    domain: ri.name.clone(),
+                    data: delete_data(&ri.data),
+                    ..Default::default()
+                }));
+                continue;
+            }
+
+            let Some(data) = record_data(&ep.record_type, target) else {
+                warn!(
+                    "Skipping deletion of {} with invalid record type of {}",
+                    ep.dns_name, ep.record_type
+                );
+                continue;
+            };
+            operations.push(Operation::Delete(DeleteRecordPayload {
+                domain: ascii(&ep.dns_name),
+                data,
+                ..Default::default()
+            }));
+        }
+    }
+
+    for ep in additions {
+        for target in ep.targets {
+            if let Some(ri) = find_record(&disabled_records, &ep.dns_name, &ep.record_type, &target)
+            {
+                operations.push(Operation::Update(UpdateRecordPayload {
+                    domain: ri.name.clone(),
+                    data: update_data(&ri.data),
+                    ttl: ep.record_ttl.or(Some(ri.ttl)),
+                    disable: Some(false),
+                    ..Default::default()
+                }));
+                continue;
+            }
+
+            let Some(data) = record_data(&ep.record_type, target) else {
+                warn!(
+                    "Skipping creation of {} with invalid record type of {}",
+                    ep.dns_name, ep.record_type
+                );
+                continue;
+            };
+            operations.push(Operation::Add(AddRecordPayload {
+                domain: ascii(&ep.dns_name),
+                ttl: ep.record_ttl,
+                data,
+                ..Default::default()
+            }));
+        }
+    }
+
+    // In-place updates and deletions first, so conflicting records are gone before adding
+    operations.sort_by_key(|op| match op {
+        Operation::Delete(_) => 0,
+        Operation::Update(_) => 1,
+        Operation::Add(_) => 2,
+    });
+    Ok(operations)
+}
+
+/// Finds additions that conflict with a CNAME, or are a CNAME conflicting with
+/// other records, either in the current records that aren't being deleted or
+/// in the other additions.
+fn find_conflicts(
+    deletions: &[Endpoint],
+    additions: &[Endpoint],
+    records: &[RecordInfo],
+) -> Vec<Conflict> {
+    let is_deleted = |name: &str, record_type: &str, target: &str| {
+        deletions.iter().any(|ep| {
+            normalize_name(&ep.dns_name) == name
+                && ep.record_type == record_type
+                && ep
+                    .targets
+                    .iter()
+                    .any(|t| normalize_target(record_type, t) == target)
+        })
+    };
+    let remaining = records
+        .iter()
+        .map(|ri| {
+            let (record_type, target) = match &ri.data {
+                RecordData::Other { record_type, data } => (record_type.clone(), data.to_string()),
+                data => record_target(data)
+                    .map(|(t, v)| (t.to_string(), normalize_target(t, &v)))
+                    .unwrap_or_default(),
+            };
+            (normalize_name(&ri.name), record_type, target)
+        })
+        .filter(|(name, record_type, target)| !is_deleted(name, record_type, target))
+        .collect::<Vec<_>>();
+    let added = additions
+        .iter()
+        .flat_map(|ep| {
+            ep.targets.iter().map(|t| {
+                (
+                    normalize_name(&ep.dns_name),
+                    ep.record_type.clone(),
+                    normalize_target(&ep.record_type, t),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut conflicts = Vec::new();
+    for (name, record_type, target) in &added {
+        let is_cname = record_type == "CNAME";
+        let conflicting = remaining
+            .iter()
+            .chain(added.iter().filter(|_| is_cname))
+            .filter(|(n, t, v)| {
+                n == name && (t != record_type || v != target) && (is_cname || t == "CNAME")
+            })
+            .map(|(_, t, v)| format!("{t} {v}"))
+            .collect::<Vec<_>>();
+        if !conflicting.is_empty() {
+            conflicts.push(Conflict {
+                dns_name: name.clone(),
+                record_type: record_type.clone(),
+                target: target.clone(),
+                conflicting,
+            });
+        }
+    }
+    conflicts
+}
+
+/// Maps Technitium record data to an external-dns record type and target.
+pub fn record_target(data: &RecordData) -> Option<(&'static str, String)> {
+    match data {
+        RecordData::A(data) => Some(("A", data.ip_address.to_string())),
+        RecordData::AAAA(data) => Some(("AAAA", data.ip_address.to_string())),
+        RecordData::CNAME(data) => Some(("CNAME", data.cname.to_string())),
+        RecordData::TXT(data) => Some(("TXT", txt::from_record_data(data))),
+        RecordData::Other { .. } => None,
+    }
+}
+
+/// Maps an external-dns record type and target to Technitium record data.
+fn record_data<T>(record_type: &str, target: String) -> Option<T>
+where
+    T: From<RecordAData> + From<RecordAAAAData> + From<RecordCNAMEData> + From<RecordTXTData>,
+{
+    match record_type {
+        "A" => Some(RecordAData { ip_address: target }.into()),
+        "AAAA" => Some(RecordAAAAData { ip_address: target }.into()),
+        "CNAME" => Some(
+            RecordCNAMEData {
+                cname: ascii(&target),
+            }
+            .into(),
+        ),
+        "TXT" => Some(txt::to_record_data(&target).into()),
+        _ => None,
+    }
+}
+
+/// Maps an external-dns record type and target to Technitium update data
+/// that keeps the record data as-is.
+fn update_record_data(
+    record_type: &str,
+    target: String,
+) -> Option<technitium::UpdateRecordPayloadRecordData> {
+    match record_type {
+        "A" => Some(technitium::RecordAUpdate::from(RecordAData { ip_address: target }).into()),
+        "AAAA" => {
+            Some(technitium::RecordAAAAUpdate::from(RecordAAAAData { ip_address: target }).into())
+        }
+        "CNAME" => Some(
+            technitium::RecordCNAMEUpdate::from(RecordCNAMEData {
+                cname: ascii(&target),
+            })
+            .into(),
+        ),
+        "TXT" => Some(technitium::RecordTXTUpdate::from(txt::to_record_data(&target)).into()),
+        _ => None,
+    }
+}
+
+/// Converts a name to the ASCII form stored by Technitium.
+///
+/// Names are validated before planning, so invalid names are passed through as-is.
+fn ascii(name: &str) -> String {
+    to_ascii(name).unwrap_or_else(|_| name.to_string())
+}
+
+/// Finds the record matching the given external-dns name, record type and target.
+fn find_record<'a>(
+    records: &[&'a RecordInfo],
+    dns_name: &str,
+    record_type: &str,
+    target: &str,
+) -> Option<&'a RecordInfo> {
+    records.iter().copied().find(|ri| {
+        normalize_name(&ri.name) == normalize_name(dns_name)
+            && record_target(&ri.data).is_some_and(|(t, v)| {
+                t == record_type && normalize_target(t, &v) == normalize_target(t, target)
+            })
+    })
+}
+
+/// Builds update data that keeps the record data as-is.
+fn update_data(data: &RecordData) -> technitium::UpdateRecordPayloadRecordData {
+    match data.clone() {
+        RecordData::A(data) => technitium::RecordAUpdate::from(data).into(),
+        RecordData::AAAA(data) => technitium::RecordAAAAUpdate::from(data).into(),
+        RecordData::CNAME(data) => technitium::RecordCNAMEUpdate::from(data).into(),
+        RecordData::TXT(data) => technitium::RecordTXTUpdate::from(data).into(),
+        RecordData::Other { record_type, data } => {
+            technitium::UpdateRecordPayloadRecordData::Other { record_type, data }
+        }
+    }
+}
+
+/// Builds delete data matching the record data as-is.
+fn delete_data(data: &RecordData) -> technitium::DeleteRecordPayloadRecordData {
+    match data.clone() {
+        RecordData::A(data) => data.into(),
+        RecordData::AAAA(data) => data.into(),
+        RecordData::CNAME(data) => data.into(),
+        RecordData::TXT(data) => data.into(),
+        RecordData::Other { record_type, data } => {
+            technitium::DeleteRecordPayloadRecordData::Other { record_type, data }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::technitium::{AddRecordPayloadRecordData, DeleteRecordPayloadRecordData};
+
+    fn endpoint(dns_name: &str, record_type: &str, targets: &[&str]) -> Endpoint {
+        Endpoint {
+            dns_name: dns_name.to_string(),
+            record_type: record_type.to_string(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            record_ttl: Some(300),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_plan_deletes_before_adds() {
+        let operations = plan(
+            vec![endpoint("a.example.com", "A", &["1.1.1.1", "2.2.2.2"])],
+            vec![],
+            vec![endpoint("a.example.com", "A", &["3.3.3.3"])],
+            &[],
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+
+        assert_eq!(operations.len(), 3);
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { domain, data: DeleteRecordPayloadRecordData::A(a), .. })
+                if domain == "a.example.com" && a.ip_address == "1.1.1.1"
+        ));
+        assert!(matches!(
+            &operations[1],
+            Operation::Delete(DeleteRecordPayload { data: DeleteRecordPayloadRecordData::A(a), .. })
+                if a.ip_address == "2.2.2.2"
+        ));
+        assert!(matches!(
+            &operations[2],
+            Operation::Add(AddRecordPayload { ttl: Some(300), data: AddRecordPayloadRecordData::A(a), .. })
+                if a.ip_address == "3.3.3.3"
+        ));
+    }
+
+    #[test]
+    fn test_plan_skips_unsupported_record_types() {
+        let operations = plan(
+            vec![endpoint("example.com", "MX", &["10 mail.example.com"])],
+            vec![],
+            vec![endpoint(
+                "example.com",
+                "SRV",
+                &["0 5 5060 sip.example.com"],
+            )],
+            &[],
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+
+        assert!(operations.is_empty());
+    }
+
+    #[test]
+    fn test_plan_enables_disabled_records() {
+        let disabled = vec![RecordInfo {
+            disabled: true,
+            name: "www.example.com".to_string(),
+            ttl: 3600,
+            data: RecordData::CNAME(RecordCNAMEData {
+                cname: "example.com".to_string(),
+            }),
+        }];
+        let operations = plan(
+            vec![],
+            vec![],
+            vec![endpoint("www.example.com", "CNAME", &["example.com"])],
+            &disabled,
+            DisabledRecordsPolicy::Enable,
+        )
+        .unwrap();
+
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(
+            &operations[0],
+            Operation::Update(UpdateRecordPayload { domain, ttl: Some(300), disable: Some(false), .. })
+                if domain == "www.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_plan_updates_only_changed_targets() {
+        let updates = pair_updates(
+            vec![endpoint(
+                "a.example.com",
+                "A",
+                &["1.1.1.1", "2.2.2.2", "3.3.3.3"],
+            )],
+            vec![endpoint(
+                "a.example.com",
+                "A",
+                &["1.1.1.1", "2.2.2.2", "4.4.4.4"],
+            )],
+        );
+        let operations = plan(vec![], updates, vec![], &[], DisabledRecordsPolicy::Show).unwrap();
+
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { data: DeleteRecordPayloadRecordData::A(a), .. })
+                if a.ip_address == "3.3.3.3"
+        ));
+        assert!(matches!(
+            &operations[1],
+            Operation::Add(AddRecordPayload { data: AddRecordPayloadRecordData::A(a), .. })
+                if a.ip_address == "4.4.4.4"
+        ));
+    }
+
+    #[test]
+    fn test_plan_updates_ttl_of_kept_targets() {
+        let mut new = endpoint("a.example.com", "A", &["1.1.1.1"]);
+        new.record_ttl = Some(60);
+        let updates = pair_updates(
+            vec![endpoint("a.example.com", "A", &["1.1.1.1"])],
+            vec![new],
+        );
+        let operations = plan(vec![], updates, vec![], &[], DisabledRecordsPolicy::Show).unwrap();
+
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(
+            &operations[0],
+            Operation::Update(UpdateRecordPayload { domain, ttl: Some(60), disable: None, .. })
+                if domain == "a.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_plan_keeps_targets_differing_only_in_form() {
+        let updates = pair_updates(
+            vec![endpoint(
+                "a.example.com",
+                "AAAA",
+                &["2001:DB8::1", "2001:db8::2"],
+            )],
+            vec![endpoint(
+                "a.example.com",
+                "AAAA",
+                &["2001:db8::1", "2001:db8::3"],
+            )],
+        );
+        assert_eq!(updates[0].changed_records(), 1);
+        let operations = plan(vec![], updates, vec![], &[], DisabledRecordsPolicy::Show).unwrap();
+
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { data: DeleteRecordPayloadRecordData::AAAA(a), .. })
+                if a.ip_address == "2001:db8::2"
+        ));
+        assert!(matches!(
+            &operations[1],
+            Operation::Add(AddRecordPayload { data: AddRecordPayloadRecordData::AAAA(a), .. })
+                if a.ip_address == "2001:db8::3"
+        ));
+    }
+
+    #[test]
+    fn test_pair_updates_without_counterpart() {
+        let updates = pair_updates(
+            vec![endpoint("a.example.com", "A", &["1.1.1.1"])],
+            vec![endpoint("b.example.com", "A", &["2.2.2.2"])],
+        );
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].old.dns_name, "a.example.com");
+        assert!(updates[0].new.targets.is_empty());
+        assert_eq!(updates[1].new.dns_name, "b.example.com");
+        assert!(updates[1].old.targets.is_empty());
+    }
+
+    fn record(name: &str, data: RecordData) -> RecordInfo {
+        RecordInfo {
+            disabled: false,
+            name: name.to_string(),
+            ttl: 3600,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_plan_detects_cname_conflicts() {
+        let records = vec![
+            record(
+                "www.example.com",
+                RecordData::A(RecordAData {
+                    ip_address: "1.1.1.1".to_string(),
+                }),
+            ),
+            record(
+                "example.com",
+                RecordData::Other {
+                    record_type: "MX".to_string(),
+                    data: serde_json::json!({"exchange": "mail.example.com"}),
+                },
+            ),
+        ];
+
+        let conflicts = plan(
+            vec![],
+            vec![],
+            vec![endpoint("www.example.com", "CNAME", &["example.com"])],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap_err();
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                dns_name: "www.example.com".to_string(),
+                record_type: "CNAME".to_string(),
+                target: "example.com".to_string(),
+                conflicting: vec!["A 1.1.1.1".to_string()],
+            }]
+        );
+
+        let conflicts = plan(
+            vec![],
+            vec![],
+            vec![endpoint("example.com", "CNAME", &["other.example.org"])],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap_err();
+        assert_eq!(conflicts[0].conflicting.len(), 1);
+        assert!(conflicts[0].conflicting[0].starts_with("MX "));
+    }
+
+    #[test]
+    fn test_plan_detects_conflicts_with_existing_cname() {
+        let records = vec![record(
+            "www.example.com",
+            RecordData::CNAME(RecordCNAMEData {
+                cname: "example.com".to_string(),
+            }),
+        )];
+
+        let conflicts = plan(
+            vec![],
+            vec![],
+            vec![endpoint("www.example.com", "TXT", &["hello"])],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap_err();
+        assert_eq!(conflicts[0].conflicting, vec!["CNAME example.com"]);
+    }
+
+    #[test]
+    fn test_plan_detects_conflicts_within_batch() {
+        let conflicts = plan(
+            vec![],
+            vec![],
+            vec![
+                endpoint("www.example.com", "A", &["1.1.1.1"]),
+                endpoint("www.example.com", "CNAME", &["example.com"]),
+            ],
+            &[],
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].record_type, "CNAME");
+        assert_eq!(conflicts[0].conflicting, vec!["A 1.1.1.1"]);
+    }
+
+    #[test]
+    fn test_plan_deletes_conflicting_records_first() {
+        let records = vec![record(
+            "www.example.com",
+            RecordData::A(RecordAData {
+                ip_address: "1.1.1.1".to_string(),
+            }),
+        )];
+        let updates = pair_updates(vec![endpoint("www.example.com", "A", &["1.1.1.1"])], vec![]);
+
+        let operations = plan(
+            vec![],
+            updates,
+            vec![endpoint("www.example.com", "CNAME", &["example.com"])],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(&operations[0], Operation::Delete(_)));
+        assert!(matches!(&operations[1], Operation::Add(_)));
+    }
+
+    #[test]
+    fn test_plan_converts_idn_to_punycode() {
+        let operations = plan(
+            vec![endpoint("bücher.example.com", "A", &["1.1.1.1"])],
+            vec![],
+            vec![endpoint(
+                "www.bücher.example.com",
+                "CNAME",
+                &["bücher.example.com"],
+            )],
+            &[],
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { domain, .. })
+                if domain == "xn--bcher-kva.example.com"
+        ));
+        assert!(matches!(
+            &operations[1],
+            Operation::Add(AddRecordPayload { domain, data: AddRecordPayloadRecordData::CNAME(c), .. })
+                if domain == "www.xn--bcher-kva.example.com" && c.cname == "xn--bcher-kva.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_plan_wildcard_records() {
+        let records = vec![
+            record(
+                "*.apps.example.com",
+                RecordData::A(RecordAData {
+                    ip_address: "1.1.1.1".to_string(),
+                }),
+            ),
+            record(
+                "www.apps.example.com",
+                RecordData::A(RecordAData {
+                    ip_address: "2.2.2.2".to_string(),
+                }),
+            ),
+        ];
+        let updates = pair_updates(
+            vec![endpoint("*.apps.example.com", "A", &["1.1.1.1"])],
+            vec![endpoint("*.apps.example.com", "A", &["3.3.3.3"])],
+        );
+
+        let operations = plan(
+            vec![],
+            updates,
+            vec![endpoint(
+                "*.other.example.com",
+                "CNAME",
+                &["apps.example.com"],
+            )],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+        assert_eq!(operations.len(), 3);
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { domain, .. }) if domain == "*.apps.example.com"
+        ));
+        let added = operations[1..]
+            .iter()
+            .map(|op| match op {
+                Operation::Add(payload) => payload.domain.as_str(),
+                _ => "",
+            })
+            .collect::<Vec<_>>();
+        assert!(added.contains(&"*.apps.example.com"));
+        assert!(added.contains(&"*.other.example.com"));
+
+        // A wildcard only conflicts with records of the wildcard name itself
+        let conflicts = plan(
+            vec![],
+            vec![],
+            vec![endpoint("*.apps.example.com", "CNAME", &["example.com"])],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap_err();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].conflicting, vec!["A 1.1.1.1"]);
+    }
+
+    #[test]
+    fn test_plan_deletes_txt_record_stored_with_quotes() {
+        // Records written before TXT values were unquoted keep their quotes in Technitium
+        let stored = RecordTXTData {
+            text: "\"heritage=external-dns,external-dns/owner=default\"".to_string(),
+            split_text: None,
+        };
+        let records = vec![record("a.example.com", RecordData::TXT(stored.clone()))];
+        let (_, target) = record_target(&records[0].data).unwrap();
+
+        let operations = plan(
+            vec![endpoint("a.example.com", "TXT", &[&target])],
+            vec![],
+            vec![],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(
+            &operations[0],
+            Operation::Delete(DeleteRecordPayload { data: DeleteRecordPayloadRecordData::TXT(data), .. })
+                if *data == stored
+        ));
+
+        let mut new = endpoint("a.example.com", "TXT", &[&target]);
+        new.record_ttl = Some(60);
+        let updates = pair_updates(
+            vec![endpoint("a.example.com", "TXT", &[&target])],
+            vec![new],
+        );
+        let operations = plan(
+            vec![],
+            updates,
+            vec![],
+            &records,
+            DisabledRecordsPolicy::Show,
+        )
+        .unwrap();
+        assert_eq!(operations.len(), 1);
+        assert!(matches!(
+            &operations[0],
+            Operation::Update(UpdateRecordPayload { data: technitium::UpdateRecordPayloadRecordData::TXT(data), .. })
+                if data.current == stored && data.text == stored.text
+        ));
+    }
+}
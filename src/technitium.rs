@@ -1,5 +1,7 @@
+mod circuit_breaker;
 mod client;
 mod models;
 
+pub use circuit_breaker::CircuitBreakerPolicy;
 pub use client::*;
 pub use models::*;
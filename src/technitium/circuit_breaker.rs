@@ -0,0 +1,166 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// When the circuit breaker opens, and for how long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerPolicy {
+    /// Consecutive failed requests after which the circuit opens, or `0` to never open it.
+    pub failure_threshold: u32,
+    /// How long requests fail fast once open, before a single request probes for recovery.
+    pub open_duration: Duration,
+}
+
+impl CircuitBreakerPolicy {
+    /// The longest open duration, which keeps the time the circuit closes representable.
+    pub const MAX_OPEN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+}
+
+impl Default for CircuitBreakerPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe request was let through, and others fail fast until it completes,
+    /// or until `until` in case it never does.
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+/// Stops sending requests to a server that keeps failing, so that callers don't each
+/// have to wait for a timeout, and periodically lets a request through to probe whether
+/// the server recovered.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    policy: CircuitBreakerPolicy,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(policy: CircuitBreakerPolicy) -> Self {
+        Self {
+            policy: CircuitBreakerPolicy {
+                open_duration: policy
+                    .open_duration
+                    .min(CircuitBreakerPolicy::MAX_OPEN_DURATION),
+                ..policy
+            },
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Returns whether a request may be sent now.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } | State::HalfOpen { until } if now < until => false,
+            State::Open { .. } | State::HalfOpen { .. } => {
+                info!("Probing whether Technitium DNS server recovered.");
+                *state = State::HalfOpen {
+                    until: now + self.policy.open_duration,
+                };
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("Technitium DNS server recovered, closing circuit breaker.");
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures.saturating_add(1),
+            // A failed probe, or a request sent before the circuit opened
+            State::Open { .. } | State::HalfOpen { .. } => self.policy.failure_threshold,
+        };
+        if self.policy.failure_threshold == 0 || failures < self.policy.failure_threshold {
+            *state = State::Closed { failures };
+            return;
+        }
+        if matches!(*state, State::Closed { .. }) {
+            warn!(
+                "Technitium DNS server failed {} requests in a row, failing fast for {:?}.",
+                failures, self.policy.open_duration
+            );
+        }
+        *state = State::Open {
+            until: Instant::now() + self.policy.open_duration,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 2,
+            open_duration: Duration::from_millis(50),
+        });
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        // Only a single probe is let through once the open duration passed
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record_failure();
+        assert!(!breaker.allow());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_circuit_breaker_huge_open_duration() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 1,
+            open_duration: Duration::MAX,
+        });
+        breaker.record_failure();
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let breaker = CircuitBreaker::new(CircuitBreakerPolicy {
+            failure_threshold: 0,
+            open_duration: Duration::from_secs(30),
+        });
+        for _ in 0..10 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow());
+    }
+}
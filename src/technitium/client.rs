@@ -1,9 +1,12 @@
+use super::circuit_breaker::{CircuitBreaker, CircuitBreakerPolicy};
 use super::models::*;
-use reqwest::Client;
+use crate::backoff::Backoff;
+use reqwest::{Certificate, Client, Identity, Proxy, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::{debug, warn};
 
 #[derive(Debug, Error)]
 pub enum TechnitiumError {
@@ -11,65 +14,246 @@ pub enum TechnitiumError {
     RequestError(#[from] reqwest::Error),
     #[error("API error: {0}")]
     ApiError(String),
+    #[error("Server responded with non-successful status code {0}")]
+    HttpError(StatusCode),
+    #[error("Invalid or expired token")]
+    InvalidToken,
+    #[error("Server is unavailable, not sending requests until it recovers")]
+    CircuitOpen,
+}
+
+impl TechnitiumError {
+    /// Whether the request may be sent again after failing with this error.
+    ///
+    /// Connection failures mean the request never reached the server, so they are always safe
+    /// to retry. Timeouts and server errors leave it unknown whether the request was applied,
+    /// so those are only retried for requests that can be repeated without side effects.
+    fn is_retryable(&self, idempotency: Idempotency) -> bool {
+        let idempotent = idempotency == Idempotency::Idempotent;
+        match self {
+            Self::RequestError(err) if err.is_connect() => true,
+            Self::RequestError(err) => idempotent && err.is_timeout(),
+            Self::HttpError(status) => {
+                idempotent && (status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS)
+            }
+            Self::ApiError(_) | Self::InvalidToken | Self::CircuitOpen => false,
+        }
+    }
+
+    /// Whether the error means the server is down or failing, rather than it rejecting the request.
+    fn is_unavailable(&self) -> bool {
+        match self {
+            Self::RequestError(err) => err.is_connect() || err.is_timeout(),
+            Self::HttpError(status) => status.is_server_error(),
+            Self::ApiError(_) | Self::InvalidToken | Self::CircuitOpen => false,
+        }
+    }
+}
+
+/// How requests that fail with a transient error are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled (with jitter) for every following one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Settings of the HTTP connection to the Technitium DNS server.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Timeout of every request, from connecting until the response body is read.
+    pub timeout: Duration,
+    /// Root certificates to trust in addition to the system ones, e.g. of a private CA.
+    pub ca_certificates: Vec<Certificate>,
+    /// Client certificate and key to authenticate with, for mutual TLS.
+    pub identity: Option<Identity>,
+    /// Accepts any server certificate, which is only meant for lab setups.
+    pub accept_invalid_certs: bool,
+    /// Proxy for all requests, instead of the one from the `HTTP_PROXY` and `HTTPS_PROXY`
+    /// environment variables.
+    pub proxy: Option<Proxy>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            ca_certificates: Vec::new(),
+            identity: None,
+            accept_invalid_certs: false,
+            proxy: None,
+        }
+    }
+}
+
+/// Whether sending a request more than once has the same effect as sending it once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Idempotency {
+    Idempotent,
+    Mutating,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TechnitiumClient {
     client: Client,
     base_url: String,
-    token: String,
+    /// Shared by all clones, so that a renewed token is used by all of them without
+    /// rebuilding the connection pool.
+    token: Arc<RwLock<String>>,
+    retry_policy: RetryPolicy,
+    /// Shared by all clones, so that the server's health is tracked across token renewals.
+    circuit_breaker: Arc<CircuitBreaker>,
 }
 
 impl TechnitiumClient {
     const ENDPOINT_LOGIN: &'static str = "/api/user/login";
+    const ENDPOINT_GET_SESSION: &'static str = "/api/user/session/get";
     const ENDPOINT_CREATE_ZONE: &'static str = "/api/zones/create";
     const ENDPOINT_LIST_ZONES: &'static str = "/api/zones/list";
     const ENDPOINT_ADD_RECORD: &'static str = "/api/zones/records/add";
     const ENDPOINT_GET_RECORDS: &'static str = "/api/zones/records/get";
+    const ENDPOINT_UPDATE_RECORD: &'static str = "/api/zones/records/update";
     const ENDPOINT_DELETE_RECORD: &'static str = "/api/zones/records/delete";
 
-    pub fn new(base_url: String, token: String, timeout: Duration) -> Self {
-        let client = Client::builder()
-            .timeout(timeout)
-            .build()
-            .expect("Failed to build reqwest client");
+    /// Creates a client with the given connection settings, which fails when they are
+    /// rejected by the TLS backend.
+    pub fn new(
+        base_url: String,
+        token: String,
+        options: &ClientOptions,
+    ) -> Result<Self, TechnitiumError> {
+        let mut builder = Client::builder()
+            .timeout(options.timeout)
+            .danger_accept_invalid_certs(options.accept_invalid_certs);
+        for certificate in &options.ca_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Some(identity) = &options.identity {
+            builder = builder.identity(identity.clone());
+        }
+        if let Some(proxy) = &options.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
 
-        Self {
-            client,
+        Ok(Self {
+            client: builder.build()?,
             base_url,
-            token,
+            token: Arc::new(RwLock::new(token)),
+            retry_policy: RetryPolicy::default(),
+            circuit_breaker: Arc::new(CircuitBreaker::new(CircuitBreakerPolicy::default())),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, policy: CircuitBreakerPolicy) -> Self {
+        self.circuit_breaker = Arc::new(CircuitBreaker::new(policy));
+        self
+    }
+
+    /// Replaces the token used by this client and all of its clones, including by retries of
+    /// requests that are already in flight.
+    pub fn set_token(&self, token: String) {
+        *self.token.write().unwrap() = token;
+    }
+
+    /// Returns a client using another token, which shares the connection pool and circuit
+    /// breaker with this one.
+    pub fn with_token(&self, token: String) -> Self {
+        Self {
+            token: Arc::new(RwLock::new(token)),
+            ..self.clone()
         }
     }
 
+    fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
+
     #[inline]
     async fn post<T: Serialize, R: for<'de> Deserialize<'de>>(
         &self,
         endpoint: &str,
+        idempotency: Idempotency,
         payload: T,
     ) -> Result<R, TechnitiumError> {
-        let req = AuthenticatedRequest {
-            token: &self.token,
-            payload,
-        };
-        let resp: NestedResponse<R> = self.post_raw(endpoint, req).await?;
+        let resp: NestedResponse<R> = self.post_raw(endpoint, idempotency, true, payload).await?;
         Ok(resp.response)
     }
 
     async fn post_raw<T: Serialize, R: for<'de> Deserialize<'de>>(
         &self,
         endpoint: &str,
+        idempotency: Idempotency,
+        authenticated: bool,
         params: T,
     ) -> Result<R, TechnitiumError> {
         let url = format!("{}{}", self.base_url, endpoint);
+        let policy = self.retry_policy;
+        let mut backoff = Backoff::new(policy.initial_backoff, policy.max_backoff).with_jitter();
+        let mut attempt = 1;
+
+        loop {
+            if !self.circuit_breaker.allow() {
+                return Err(TechnitiumError::CircuitOpen);
+            }
+            // The token is read for every attempt, so retries use a token renewed in the meantime
+            let result = if authenticated {
+                let token = self.token();
+                let req = AuthenticatedRequest {
+                    token: &token,
+                    payload: &params,
+                };
+                self.send(&url, &req).await
+            } else {
+                self.send(&url, &params).await
+            };
+            match &result {
+                Err(err) if err.is_unavailable() => self.circuit_breaker.record_failure(),
+                _ => self.circuit_breaker.record_success(),
+            }
+
+            match result {
+                Err(err) if attempt < policy.max_attempts && err.is_retryable(idempotency) => {
+                    let delay = backoff.next_delay();
+                    warn!(
+                        "Request to {} failed (attempt {}/{}), retrying in {:?}: {}",
+                        endpoint, attempt, policy.max_attempts, delay, err
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send<T: Serialize, R: for<'de> Deserialize<'de>>(
+        &self,
+        url: &str,
+        params: &T,
+    ) -> Result<R, TechnitiumError> {
         debug!("Sending POST request to {}", url);
 
-        let response = self.client.post(&url).form(&params).send().await?;
+        let response = self.client.post(url).form(params).send().await?;
 
         if !response.status().is_success() {
-            return Err(TechnitiumError::ApiError(format!(
-                "Server responded with non-successful status code {}",
-                response.status()
-            )));
+            return Err(TechnitiumError::HttpError(response.status()));
         }
 
         let response: Response<R> = response.json().await?;
@@ -82,9 +266,7 @@ impl TechnitiumClient {
                     .error_message
                     .unwrap_or("Unknown server error".to_string()),
             )),
-            ResponseStatus::InvalidToken => {
-                Err(TechnitiumError::ApiError("Invalid token".to_string()))
-            }
+            ResponseStatus::InvalidToken => Err(TechnitiumError::InvalidToken),
             ResponseStatus::Unknown(status) => Err(TechnitiumError::ApiError(format!(
                 "Unexpected response status ({})",
                 status
@@ -94,7 +276,28 @@ impl TechnitiumClient {
 
     #[inline]
     pub async fn login(&self, payload: LoginPayload) -> Result<LoginResponse, TechnitiumError> {
-        self.post_raw(Self::ENDPOINT_LOGIN, payload).await
+        self.post_raw(
+            Self::ENDPOINT_LOGIN,
+            Idempotency::Idempotent,
+            false,
+            payload,
+        )
+        .await
+    }
+
+    /// Returns the session the token belongs to, which is a cheap way to validate a token.
+    #[inline]
+    pub async fn get_session(
+        &self,
+        payload: GetSessionPayload,
+    ) -> Result<GetSessionResponse, TechnitiumError> {
+        self.post_raw(
+            Self::ENDPOINT_GET_SESSION,
+            Idempotency::Idempotent,
+            true,
+            payload,
+        )
+        .await
     }
 
     #[inline]
@@ -102,7 +305,8 @@ impl TechnitiumClient {
         &self,
         payload: CreateZonePayload,
     ) -> Result<CreateZoneResponse, TechnitiumError> {
-        self.post(Self::ENDPOINT_CREATE_ZONE, payload).await
+        self.post(Self::ENDPOINT_CREATE_ZONE, Idempotency::Mutating, payload)
+            .await
     }
 
     #[inline]
@@ -110,7 +314,8 @@ impl TechnitiumClient {
         &self,
         payload: ListZonesPayload,
     ) -> Result<ListZonesResponse, TechnitiumError> {
-        self.post(Self::ENDPOINT_LIST_ZONES, payload).await
+        self.post(Self::ENDPOINT_LIST_ZONES, Idempotency::Idempotent, payload)
+            .await
     }
 
     #[inline]
@@ -118,7 +323,8 @@ impl TechnitiumClient {
         &self,
         payload: AddRecordPayload,
     ) -> Result<AddRecordResponse, TechnitiumError> {
-        self.post(Self::ENDPOINT_ADD_RECORD, payload).await
+        self.post(Self::ENDPOINT_ADD_RECORD, Idempotency::Mutating, payload)
+            .await
     }
 
     #[inline]
@@ -126,7 +332,17 @@ impl TechnitiumClient {
         &self,
         payload: GetRecordsPayload,
     ) -> Result<GetRecordsResponse, TechnitiumError> {
-        self.post(Self::ENDPOINT_GET_RECORDS, payload).await
+        self.post(Self::ENDPOINT_GET_RECORDS, Idempotency::Idempotent, payload)
+            .await
+    }
+
+    #[inline]
+    pub async fn update_record(
+        &self,
+        payload: UpdateRecordPayload,
+    ) -> Result<UpdateRecordResponse, TechnitiumError> {
+        self.post(Self::ENDPOINT_UPDATE_RECORD, Idempotency::Mutating, payload)
+            .await
     }
 
     #[inline]
@@ -134,7 +350,8 @@ impl TechnitiumClient {
         &self,
         payload: DeleteRecordPayload,
     ) -> Result<DeleteRecordResponse, TechnitiumError> {
-        self.post(Self::ENDPOINT_DELETE_RECORD, payload).await
+        self.post(Self::ENDPOINT_DELETE_RECORD, Idempotency::Mutating, payload)
+            .await
     }
 }
 
@@ -201,8 +418,9 @@ mod tests {
         let client = TechnitiumClient::new(
             server.url(),
             "dummy_token".to_string(),
-            Duration::from_secs(30),
-        );
+            &ClientOptions::default(),
+        )
+        .unwrap();
         let res = client
             .login(LoginPayload {
                 username: "admin".to_string(),
@@ -222,6 +440,76 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_client_get_session() {
+        let mut server = mockito::Server::new_async().await;
+
+        let response_data = json!({
+            "status": "ok",
+            "displayName": "External DNS",
+            "username": "external-dns",
+            "token": "api_token",
+            "info": {
+                "version": "13.0",
+            },
+        });
+
+        let mock = server
+            .mock("POST", "/api/user/session/get")
+            .match_header("content-type", "application/x-www-form-urlencoded")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "api_token".into(),
+            ))
+            .with_status(200)
+            .with_body(response_data.to_string())
+            .create();
+
+        let client = TechnitiumClient::new(
+            server.url(),
+            "api_token".to_string(),
+            &ClientOptions::default(),
+        )
+        .unwrap();
+        let res = client
+            .get_session(GetSessionPayload::default())
+            .await
+            .unwrap();
+
+        mock.assert();
+        assert_eq!(
+            res,
+            GetSessionResponse {
+                display_name: "External DNS".to_string(),
+                username: "external-dns".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_get_session_invalid_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let response_data = json!({
+            "status": "invalid-token",
+            "errorMessage": "Invalid token or session expired.",
+        });
+
+        let mock = server
+            .mock("POST", "/api/user/session/get")
+            .with_status(200)
+            .with_body(response_data.to_string())
+            .create();
+
+        let client =
+            TechnitiumClient::new(server.url(), "bad".to_string(), &ClientOptions::default())
+                .unwrap();
+        let res = client.get_session(GetSessionPayload::default()).await;
+
+        mock.assert();
+        assert!(matches!(res, Err(TechnitiumError::InvalidToken)));
+    }
+
     #[tokio::test]
     async fn test_client_add_zone() {
         let mut server = mockito::Server::new_async().await;
@@ -245,7 +533,8 @@ mod tests {
             .create();
 
         let client =
-            TechnitiumClient::new(server.url(), "token".to_string(), Duration::from_secs(30));
+            TechnitiumClient::new(server.url(), "token".to_string(), &ClientOptions::default())
+                .unwrap();
         let res = client
             .create_zone(CreateZonePayload {
                 zone: "example.com".to_string(),
@@ -304,7 +593,8 @@ mod tests {
             .create();
 
         let client =
-            TechnitiumClient::new(server.url(), "token".to_string(), Duration::from_secs(30));
+            TechnitiumClient::new(server.url(), "token".to_string(), &ClientOptions::default())
+                .unwrap();
         let res = client
             .add_record(AddRecordPayload {
                 domain: "example.com".to_string(),
@@ -338,4 +628,186 @@ mod tests {
             }
         );
     }
+
+    #[tokio::test]
+    async fn test_client_set_token_applies_to_clones() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mock = server
+            .mock("POST", "/api/user/session/get")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "renewed".into(),
+            ))
+            .with_body(
+                json!({
+                    "status": "ok",
+                    "displayName": "Administrator",
+                    "username": "admin",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let client = TechnitiumClient::new(
+            server.url(),
+            "expired".to_string(),
+            &ClientOptions::default(),
+        )
+        .unwrap();
+        let clone = client.clone();
+        client.set_token("renewed".to_string());
+        clone
+            .get_session(GetSessionPayload::default())
+            .await
+            .unwrap();
+
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_client_retries_with_renewed_token() {
+        let mut server = mockito::Server::new_async().await;
+
+        let failed = server
+            .mock("POST", "/api/user/session/get")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "expired".into(),
+            ))
+            .with_status(503)
+            .expect(1)
+            .create();
+        let renewed = server
+            .mock("POST", "/api/user/session/get")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "token".into(),
+                "renewed".into(),
+            ))
+            .with_body(
+                json!({
+                    "status": "ok",
+                    "displayName": "Administrator",
+                    "username": "admin",
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let client = TechnitiumClient::new(
+            server.url(),
+            "expired".to_string(),
+            &ClientOptions::default(),
+        )
+        .unwrap()
+        .with_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(400),
+            max_backoff: Duration::from_millis(400),
+        });
+        let renew = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.set_token("renewed".to_string());
+        };
+        let (res, ()) = tokio::join!(client.get_session(GetSessionPayload::default()), renew);
+
+        res.unwrap();
+        failed.assert();
+        renewed.assert();
+    }
+
+    fn fast_retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_retries_idempotent_request() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mock = server
+            .mock("POST", "/api/zones/records/get")
+            .with_status(503)
+            .expect(3)
+            .create();
+
+        let client =
+            TechnitiumClient::new(server.url(), "token".to_string(), &ClientOptions::default())
+                .unwrap()
+                .with_retry_policy(fast_retry_policy());
+        let res = client
+            .get_records(GetRecordsPayload {
+                domain: "example.com".to_string(),
+                ..Default::default()
+            })
+            .await;
+
+        mock.assert();
+        assert!(matches!(
+            res,
+            Err(TechnitiumError::HttpError(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_does_not_retry_mutating_request() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mock = server
+            .mock("POST", "/api/zones/create")
+            .with_status(503)
+            .expect(1)
+            .create();
+
+        let client =
+            TechnitiumClient::new(server.url(), "token".to_string(), &ClientOptions::default())
+                .unwrap()
+                .with_retry_policy(fast_retry_policy());
+        let res = client
+            .create_zone(CreateZonePayload {
+                zone: "example.com".to_string(),
+                zone_type: ZoneType::Primary,
+                ..Default::default()
+            })
+            .await;
+
+        mock.assert();
+        assert!(matches!(
+            res,
+            Err(TechnitiumError::HttpError(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_fails_fast_when_circuit_open() {
+        let mut server = mockito::Server::new_async().await;
+
+        let mock = server
+            .mock("POST", "/api/zones/list")
+            .with_status(500)
+            .expect(2)
+            .create();
+
+        let client =
+            TechnitiumClient::new(server.url(), "token".to_string(), &ClientOptions::default())
+                .unwrap()
+                .with_retry_policy(fast_retry_policy())
+                .with_circuit_breaker(CircuitBreakerPolicy {
+                    failure_threshold: 2,
+                    open_duration: Duration::from_secs(30),
+                });
+
+        let res = client.list_zones(ListZonesPayload::default()).await;
+        assert!(matches!(res, Err(TechnitiumError::CircuitOpen)));
+        // The circuit breaker stays open when the token is renewed
+        client.set_token("renewed".to_string());
+        let res = client.list_zones(ListZonesPayload::default()).await;
+        assert!(matches!(res, Err(TechnitiumError::CircuitOpen)));
+
+        mock.assert();
+    }
 }
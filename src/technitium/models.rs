@@ -1,6 +1,8 @@
+#![allow(clippy::upper_case_acronyms)]
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct LoginPayload {
     /// The username for the user account. The built-in administrator username on the DNS server is `admin`.
     #[serde(rename = "user")]
@@ -18,7 +20,17 @@ pub struct LoginResponse {
     pub token: String,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct GetSessionPayload {}
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct GetSessionResponse {
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+    pub username: String,
+}
+
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct CreateZonePayload {
     pub zone: String,
     #[serde(rename = "type")]
@@ -34,7 +46,7 @@ pub struct CreateZoneResponse {
     pub domain: String,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct ListZonesPayload {
     pub zone: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -56,7 +68,7 @@ pub struct ListZonesResponse {
     pub zones: Vec<ZoneInfo>,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct AddRecordPayload {
     #[serde(rename = "domain")]
     pub domain: String,
@@ -79,7 +91,7 @@ pub struct AddRecordPayload {
     pub expiry_ttl: Option<u32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum AddRecordPayloadRecordData {
     #[serde(rename = "A")]
@@ -101,10 +113,10 @@ pub enum AddRecordPayloadRecordData {
 
 impl Default for AddRecordPayloadRecordData {
     fn default() -> Self {
-        return Self::Other {
+        Self::Other {
             record_type: "".to_string(),
             data: serde_json::Value::String("".to_string()),
-        };
+        }
     }
 }
 
@@ -139,7 +151,7 @@ pub struct AddRecordResponse {
     pub added_record: RecordInfo,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct GetRecordsPayload {
     pub domain: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -155,7 +167,7 @@ pub struct GetRecordsResponse {
     pub records: Vec<RecordInfo>,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Serialize, Default, Clone)]
 pub struct DeleteRecordPayload {
     #[serde(rename = "domain")]
     pub domain: String,
@@ -166,7 +178,7 @@ pub struct DeleteRecordPayload {
     pub zone: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 #[serde(tag = "type")]
 pub enum DeleteRecordPayloadRecordData {
     #[serde(rename = "A")]
@@ -222,6 +234,83 @@ impl From<RecordTXTData> for DeleteRecordPayloadRecordData {
 #[derive(Debug, Deserialize, Eq, PartialEq)]
 pub struct DeleteRecordResponse {}
 
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct UpdateRecordPayload {
+    #[serde(rename = "domain")]
+    pub domain: String,
+    #[serde(flatten)]
+    pub data: UpdateRecordPayloadRecordData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "zone")]
+    pub zone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "ttl")]
+    pub ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "disable")]
+    pub disable: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "type")]
+pub enum UpdateRecordPayloadRecordData {
+    #[serde(rename = "A")]
+    A(RecordAUpdate),
+    #[serde(rename = "AAAA")]
+    AAAA(RecordAAAAUpdate),
+    #[serde(rename = "CNAME")]
+    CNAME(RecordCNAMEUpdate),
+    #[serde(rename = "TXT")]
+    TXT(RecordTXTUpdate),
+    #[serde(untagged)]
+    Other {
+        #[serde(rename = "type")]
+        record_type: String,
+        #[serde(rename = "rdata")]
+        data: serde_json::Value,
+    },
+}
+
+impl Default for UpdateRecordPayloadRecordData {
+    fn default() -> Self {
+        Self::Other {
+            record_type: "".to_string(),
+            data: serde_json::Value::String("".to_string()),
+        }
+    }
+}
+
+impl From<RecordAUpdate> for UpdateRecordPayloadRecordData {
+    fn from(data: RecordAUpdate) -> Self {
+        Self::A(data)
+    }
+}
+
+impl From<RecordAAAAUpdate> for UpdateRecordPayloadRecordData {
+    fn from(data: RecordAAAAUpdate) -> Self {
+        Self::AAAA(data)
+    }
+}
+
+impl From<RecordCNAMEUpdate> for UpdateRecordPayloadRecordData {
+    fn from(data: RecordCNAMEUpdate) -> Self {
+        Self::CNAME(data)
+    }
+}
+
+impl From<RecordTXTUpdate> for UpdateRecordPayloadRecordData {
+    fn from(data: RecordTXTUpdate) -> Self {
+        Self::TXT(data)
+    }
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct UpdateRecordResponse {
+    pub zone: ZoneInfo,
+    #[serde(rename = "updatedRecord")]
+    pub updated_record: RecordInfo,
+}
+
 #[derive(Debug, Deserialize, Eq, PartialEq)]
 pub struct ZoneInfo {
     pub name: String,
@@ -241,7 +330,7 @@ pub struct RecordInfo {
     pub data: RecordData,
 }
 
-#[derive(Debug, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Deserialize, Eq, PartialEq, Clone)]
 #[serde(tag = "type", content = "rData")]
 pub enum RecordData {
     #[serde(rename = "A")]
@@ -292,6 +381,11 @@ pub struct RecordCNAMEData {
 pub struct RecordTXTData {
     #[serde(rename = "text")]
     pub text: String,
+    /// Whether newlines in `text` separate multiple character-strings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    #[serde(rename = "splitText")]
+    pub split_text: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
@@ -351,6 +445,9 @@ pub struct RecordTXTUpdate {
     pub current: RecordTXTData,
     #[serde(rename = "newText")]
     pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "newSplitText")]
+    pub split_text: Option<bool>,
 }
 
 impl From<RecordTXTData> for RecordTXTUpdate {
@@ -358,11 +455,12 @@ impl From<RecordTXTData> for RecordTXTUpdate {
         Self {
             current: data.clone(),
             text: data.text,
+            split_text: data.split_text,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Default, Eq, PartialEq, Clone)]
 pub enum ZoneType {
     #[default]
     #[serde(rename = "Primary")]
@@ -381,7 +479,7 @@ pub enum ZoneType {
     SecondaryCatalog,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub enum Protocol {
     #[default]
     #[serde(rename = "Udp")]
@@ -520,6 +618,7 @@ mod tests {
             ttl: Some(3600),
             data: AddRecordPayloadRecordData::TXT(RecordTXTData {
                 text: "v=spf1 include:example.com -all".to_string(),
+                split_text: None,
             }),
             ..Default::default()
         })
@@ -530,4 +629,63 @@ mod tests {
 
         assert_eq!(serialized, expected);
     }
+
+    #[test]
+    fn test_update_record_payload_serialization_for_a_record() {
+        let serialized = serde_urlencoded::to_string(&UpdateRecordPayload {
+            domain: "example.com".to_string(),
+            ttl: Some(3600),
+            disable: Some(false),
+            data: RecordAUpdate::from(RecordAData {
+                ip_address: "1.1.1.1".to_string(),
+            })
+            .into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let expected = "domain=example.com&type=A&ipAddress=1.1.1.1&newIpAddress=1.1.1.1&ttl=3600&disable=false";
+
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn test_add_record_payload_serialization_for_split_txt_record() {
+        let serialized = serde_urlencoded::to_string(&AddRecordPayload {
+            domain: "example.com".to_string(),
+            data: AddRecordPayloadRecordData::TXT(RecordTXTData {
+                text: "part1\npart2".to_string(),
+                split_text: Some(true),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let expected = "domain=example.com&type=TXT&text=part1%0Apart2&splitText=true";
+
+        assert_eq!(serialized, expected);
+    }
+
+    #[test]
+    fn test_record_info_split_txt_deserialization() {
+        let data = json!({
+            "disabled": false,
+            "name": "example.com",
+            "type": "TXT",
+            "ttl": 3600,
+            "rData": {
+                "text": "part1\npart2",
+                "splitText": true
+            },
+        });
+
+        let record: RecordInfo = serde_json::from_value(data).unwrap();
+        assert_eq!(
+            record.data,
+            RecordData::TXT(RecordTXTData {
+                text: "part1\npart2".to_string(),
+                split_text: Some(true),
+            })
+        );
+    }
 }
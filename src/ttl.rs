@@ -0,0 +1,193 @@
+use crate::pattern::RecordSelector;
+use std::str::FromStr;
+
+/// Default, minimum and maximum TTL applied to desired endpoints.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct TtlLimits {
+    pub default: Option<u32>,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+impl TtlLimits {
+    /// Fills in the default TTL if none is set, and clamps it to the minimum and maximum.
+    pub fn apply(&self, ttl: Option<u32>) -> Option<u32> {
+        ttl.or(self.default).map(|ttl| {
+            let ttl = self.min.map_or(ttl, |min| ttl.max(min));
+            self.max.map_or(ttl, |max| ttl.min(max))
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min, self.max)
+            && min > max
+        {
+            return Err(format!(
+                "minimum TTL {min} is larger than maximum TTL {max}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the limits with unset values taken from `fallback`.
+    pub fn or(self, fallback: TtlLimits) -> TtlLimits {
+        TtlLimits {
+            default: self.default.or(fallback.default),
+            min: self.min.or(fallback.min),
+            max: self.max.or(fallback.max),
+        }
+    }
+}
+
+/// Parsed from a comma-separated list of `default:TTL`, `min:TTL` and `max:TTL`.
+impl FromStr for TtlLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = TtlLimits::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once(':')
+                .ok_or_else(|| format!("invalid TTL setting: {setting}"))?;
+            let value = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid TTL: {value}"))?;
+            match key.trim() {
+                "default" => limits.default = Some(value),
+                "min" => limits.min = Some(value),
+                "max" => limits.max = Some(value),
+                key => return Err(format!("unknown TTL setting: {key}")),
+            }
+        }
+        limits.validate()?;
+        Ok(limits)
+    }
+}
+
+/// TTL limits for the records matching a selector.
+///
+/// Parsed from `selector=limits`, e.g. `*.dyn.example.com:A,AAAA=default:60,min:30`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TtlRule {
+    pub selector: RecordSelector,
+    pub limits: TtlLimits,
+}
+
+impl FromStr for TtlRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (selector, limits) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid TTL rule: {s}"))?;
+        Ok(Self {
+            selector: selector.parse()?,
+            limits: limits.parse()?,
+        })
+    }
+}
+
+impl TtlRule {
+    /// Checks the limits of the rule once the unset values are taken from `fallback`,
+    /// like they are when the rule is applied.
+    pub fn validate(&self, fallback: TtlLimits) -> Result<(), String> {
+        self.limits
+            .or(fallback)
+            .validate()
+            .map_err(|e| format!("TTL rule {}: {e}", self.selector))
+    }
+}
+
+/// The TTL limits applied to desired endpoints, where the first matching rule
+/// takes precedence over the global limits.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TtlPolicy {
+    pub limits: TtlLimits,
+    pub rules: Vec<TtlRule>,
+}
+
+impl TtlPolicy {
+    pub fn apply(&self, name: &str, record_type: &str, ttl: Option<u32>) -> Option<u32> {
+        let limits = self
+            .rules
+            .iter()
+            .find(|r| r.selector.matches(name, record_type))
+            .map_or(self.limits, |r| r.limits.or(self.limits));
+        limits.apply(ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_apply() {
+        let limits = TtlLimits {
+            default: Some(300),
+            min: Some(60),
+            max: Some(3600),
+        };
+        assert_eq!(limits.apply(None), Some(300));
+        assert_eq!(limits.apply(Some(5)), Some(60));
+        assert_eq!(limits.apply(Some(86400)), Some(3600));
+        assert_eq!(limits.apply(Some(600)), Some(600));
+        assert_eq!(TtlLimits::default().apply(None), None);
+    }
+
+    #[test]
+    fn test_limits_parsing() {
+        assert_eq!(
+            "default:300, min:60".parse(),
+            Ok(TtlLimits {
+                default: Some(300),
+                min: Some(60),
+                max: None,
+            })
+        );
+        assert!("min:600,max:60".parse::<TtlLimits>().is_err());
+        assert!("low:60".parse::<TtlLimits>().is_err());
+        assert!("min:-1".parse::<TtlLimits>().is_err());
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let policy = TtlPolicy {
+            limits: TtlLimits {
+                default: Some(3600),
+                min: Some(300),
+                max: None,
+            },
+            rules: vec!["*.dyn.example.com:A=default:60,min:30".parse().unwrap()],
+        };
+
+        assert_eq!(policy.apply("host.dyn.example.com", "A", None), Some(60));
+        assert_eq!(
+            policy.apply("host.dyn.example.com", "A", Some(10)),
+            Some(30)
+        );
+        assert_eq!(
+            policy.apply("host.dyn.example.com", "TXT", None),
+            Some(3600)
+        );
+        assert_eq!(policy.apply("www.example.com", "A", Some(10)), Some(300));
+    }
+
+    #[test]
+    fn test_rule_validation() {
+        let rule: TtlRule = "*.dyn.example.com:A,AAAA=min:600".parse().unwrap();
+        let limits = |max| TtlLimits {
+            max: Some(max),
+            ..Default::default()
+        };
+        assert_eq!(rule.validate(limits(3600)), Ok(()));
+        assert_eq!(
+            rule.validate(limits(300)),
+            Err(
+                "TTL rule *.dyn.example.com:A,AAAA: minimum TTL 600 is larger than maximum TTL 300"
+                    .to_string()
+            )
+        );
+    }
+}
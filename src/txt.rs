@@ -0,0 +1,198 @@
+//! Conversion between external-dns TXT targets and TXT character-strings.
+//!
+//! A TXT record consists of one or more character-strings of at most 255
+//! bytes each. external-dns sends TXT targets either as plain text, or as one
+//! or more quoted strings (e.g. `"v=DKIM1; k=rsa; " "p=MIIB..."`). Technitium
+//! stores the character-strings separated by newlines when `splitText` is set.
+
+use crate::technitium::RecordTXTData;
+
+const MAX_STRING_LENGTH: usize = 255;
+
+/// Splits a TXT target into its character-strings.
+///
+/// Targets consisting only of quoted strings are unquoted, anything else is
+/// taken as a single string. Strings longer than 255 bytes are split up.
+pub fn parse(target: &str) -> Vec<String> {
+    let strings = parse_quoted(target).unwrap_or_else(|| vec![target.to_string()]);
+    strings.iter().flat_map(|s| split_long(s)).collect()
+}
+
+/// Formats character-strings as a TXT target.
+///
+/// A single string, or strings that are the result of splitting up a single
+/// long string, are formatted as plain text. Otherwise each string is quoted.
+pub fn format(strings: &[String]) -> String {
+    let split_up = strings
+        .split_last()
+        .is_some_and(|(_, rest)| rest.iter().all(|s| s.len() == MAX_STRING_LENGTH));
+    if split_up {
+        return strings.concat();
+    }
+    strings
+        .iter()
+        .map(|s| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Returns the canonical form of a TXT target.
+pub fn normalize(target: &str) -> String {
+    format(&parse(target))
+}
+
+/// Converts a TXT target to Technitium record data.
+pub fn to_record_data(target: &str) -> RecordTXTData {
+    let strings = parse(target);
+    RecordTXTData {
+        split_text: (strings.len() > 1).then_some(true),
+        text: strings.join("\n"),
+    }
+}
+
+/// Converts Technitium record data to a TXT target.
+pub fn from_record_data(data: &RecordTXTData) -> String {
+    let strings = match data.split_text {
+        Some(true) => data.text.split('\n').flat_map(split_long).collect(),
+        _ => split_long(&data.text),
+    };
+    format(&strings)
+}
+
+/// Parses a sequence of whitespace separated quoted strings.
+fn parse_quoted(target: &str) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut chars = target.trim().chars();
+    loop {
+        match chars.next() {
+            None => break,
+            Some(c) if c.is_whitespace() => continue,
+            Some('"') => {}
+            Some(_) => return None,
+        }
+        let mut s = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break,
+                '\\' => s.push(chars.next()?),
+                c => s.push(c),
+            }
+        }
+        strings.push(s);
+    }
+    (!strings.is_empty()).then_some(strings)
+}
+
+/// Splits a string into strings of at most 255 bytes, without splitting characters.
+fn split_long(s: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut rest = s;
+    while rest.len() > MAX_STRING_LENGTH {
+        let mut at = MAX_STRING_LENGTH;
+        while !rest.is_char_boundary(at) {
+            at -= 1;
+        }
+        let (head, tail) = rest.split_at(at);
+        strings.push(head.to_string());
+        rest = tail;
+    }
+    strings.push(rest.to_string());
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dkim_key() -> String {
+        format!(
+            "v=DKIM1; k=rsa; p={}",
+            "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA".repeat(9)
+        )
+    }
+
+    #[test]
+    fn test_parse_plain_text() {
+        assert_eq!(parse("v=spf1 -all"), vec!["v=spf1 -all"]);
+        assert_eq!(parse("a \"quoted\" word"), vec!["a \"quoted\" word"]);
+    }
+
+    #[test]
+    fn test_parse_quoted_strings() {
+        assert_eq!(parse("\"hello world\""), vec!["hello world"]);
+        assert_eq!(parse("\"part1\" \"part2\""), vec!["part1", "part2"]);
+        assert_eq!(parse(r#""say \"hi\" \\o/""#), vec![r#"say "hi" \o/"#]);
+        assert_eq!(parse("\"unterminated"), vec!["\"unterminated"]);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(&["hello".to_string()]), "hello");
+        assert_eq!(
+            format(&["a".to_string(), "b \"c\"".to_string()]),
+            r#""a" "b \"c\"""#
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("\"heritage=external-dns\""),
+            "heritage=external-dns"
+        );
+        assert_eq!(normalize("\"a\"   \"b\""), "\"a\" \"b\"");
+        assert_eq!(normalize("plain"), "plain");
+    }
+
+    #[test]
+    fn test_dkim_record_round_trip() {
+        let key = dkim_key();
+        assert!(key.len() > 255);
+
+        let data = to_record_data(&key);
+        assert_eq!(data.split_text, Some(true));
+        let strings = data.text.split('\n').collect::<Vec<_>>();
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0].len(), 255);
+        assert_eq!(strings.concat(), key);
+
+        assert_eq!(from_record_data(&data), key);
+        assert_eq!(normalize(&key), key);
+    }
+
+    #[test]
+    fn test_quoted_dkim_record_round_trip() {
+        let key = dkim_key();
+        let (a, b) = key.split_at(200);
+        let target = format!("\"{a}\" \"{b}\"");
+
+        let data = to_record_data(&target);
+        assert_eq!(data.split_text, Some(true));
+        assert_eq!(data.text, format!("{a}\n{b}"));
+        assert_eq!(from_record_data(&data), target);
+        assert_eq!(normalize(&target), target);
+
+        // Splitting at the maximum length is the same as not splitting at all
+        let (a, b) = key.split_at(255);
+        assert_eq!(normalize(&format!("\"{a}\" \"{b}\"")), key);
+    }
+
+    #[test]
+    fn test_long_record_stored_without_split_text() {
+        let key = dkim_key();
+        let data = RecordTXTData {
+            text: key.clone(),
+            split_text: None,
+        };
+        assert_eq!(from_record_data(&data), key);
+    }
+
+    #[test]
+    fn test_split_long_keeps_characters_intact() {
+        let text = "é".repeat(200);
+        let strings = split_long(&text);
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0].len(), 254);
+        assert_eq!(strings.concat(), text);
+    }
+}